@@ -1,32 +1,126 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
 
 use tokio::{
-    io::{AsyncWriteExt, BufWriter},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadHalf, WriteHalf},
+    net::TcpListener,
 };
 
+pub use self::body::{Body, BodyReader};
 use self::{
-    encoders::EncoderFn, request::{Request, RequestParser, RequestParserError}, response::Response, router::Router
+    encoders::EncoderFn,
+    header::Headers,
+    mime::MimeTypes,
+    multipart::PartLimits,
+    request::{
+        BodyStream, Framing, Incoming, Limits, Metadata, Request, RequestError, RequestParser,
+        RequestParserError, SharedIncoming,
+    },
+    response::Response,
+    router::Router,
+    site::StaticSite,
+    status::StatusCode,
 };
 
+pub mod body;
+pub mod chunked;
+pub mod conditional;
+pub mod encoders;
+pub mod files;
 pub mod handlers;
 pub mod header;
 pub mod helpers;
+pub mod json;
 pub mod middleware;
+pub mod mime;
+pub mod multipart;
+pub mod range;
 pub mod request;
 pub mod response;
 pub mod router;
+pub mod site;
 pub mod status;
-pub mod encoders;
 
-pub struct Body {
-    pub data: Vec<u8>,
-}
-
-#[derive(PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Method {
     GET,
+    HEAD,
     POST,
+    PUT,
+    DELETE,
+    PATCH,
+    OPTIONS,
+    CONNECT,
+    TRACE,
+    /// Any other syntactically valid method token, e.g. `PROPFIND`.
+    Extension(String),
+}
+
+impl Method {
+    /// Parses a method token, returning `None` if it is not a valid token.
+    pub fn from_token(token: &str) -> Option<Self> {
+        let method = match token {
+            "GET" => Method::GET,
+            "HEAD" => Method::HEAD,
+            "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "DELETE" => Method::DELETE,
+            "PATCH" => Method::PATCH,
+            "OPTIONS" => Method::OPTIONS,
+            "CONNECT" => Method::CONNECT,
+            "TRACE" => Method::TRACE,
+            token if !token.is_empty() && token.bytes().all(helpers::is_tchar) => {
+                Method::Extension(token.to_string())
+            }
+            _ => return None,
+        };
+        Some(method)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::GET => "GET",
+            Method::HEAD => "HEAD",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::PATCH => "PATCH",
+            Method::OPTIONS => "OPTIONS",
+            Method::CONNECT => "CONNECT",
+            Method::TRACE => "TRACE",
+            Method::Extension(token) => token,
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 #[derive(Clone)]
@@ -34,6 +128,10 @@ pub struct State(Arc<StateInner>);
 
 struct StateInner {
     pub file_dir: Option<String>,
+    pub mime_types: MimeTypes,
+    pub static_site: Option<StaticSite>,
+    pub create_dirs: bool,
+    pub multipart_limits: PartLimits,
     pub supported_encodings: HashMap<String, EncoderFn>,
 }
 
@@ -43,8 +141,30 @@ impl StateInner {
         self
     }
 
+    fn mime_types(mut self, mime_types: MimeTypes) -> Self {
+        self.mime_types = mime_types;
+        self
+    }
+
+    fn static_site(mut self, static_site: Option<StaticSite>) -> Self {
+        self.static_site = static_site;
+        self
+    }
+
+    fn create_dirs(mut self, create_dirs: bool) -> Self {
+        self.create_dirs = create_dirs;
+        self
+    }
+
+    fn multipart_limits(mut self, limits: PartLimits) -> Self {
+        self.multipart_limits = limits;
+        self
+    }
+
     fn encoding<E>(mut self, encoding: String, encoder: E) -> Self
-    where E: Fn(Body) -> Result<Body, anyhow::Error> + Send + Sync + 'static {
+    where
+        E: Fn(Body) -> Result<Body, anyhow::Error> + Send + Sync + 'static,
+    {
         self.supported_encodings.insert(encoding, Box::new(encoder));
         self
     }
@@ -58,12 +178,32 @@ impl State {
     fn builder() -> StateInner {
         StateInner {
             file_dir: None,
+            mime_types: MimeTypes::default(),
+            static_site: None,
+            create_dirs: false,
+            multipart_limits: PartLimits::default(),
             supported_encodings: HashMap::new(),
         }
     }
 
     fn file_dir(&self) -> Option<&str> {
-        self.0.file_dir.as_ref().map(|s| s.as_str())
+        self.0.file_dir.as_deref()
+    }
+
+    fn mime_types(&self) -> &MimeTypes {
+        &self.0.mime_types
+    }
+
+    fn static_site(&self) -> Option<&StaticSite> {
+        self.0.static_site.as_ref()
+    }
+
+    fn create_dirs(&self) -> bool {
+        self.0.create_dirs
+    }
+
+    fn multipart_limits(&self) -> &PartLimits {
+        &self.0.multipart_limits
     }
 
     fn supported_encoding(&self, encoding: &str) -> bool {
@@ -75,20 +215,79 @@ impl State {
     }
 }
 
-pub async fn run_server(listener: TcpListener, file_directory: Option<String>) {
+/// Server wide settings passed to [`run_server`].
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Directory served under `/files/`, the routes are left out without one.
+    pub file_dir: Option<String>,
+    /// Content types of the served files.
+    pub mime_types: MimeTypes,
+    /// Serve index files and listings for directories, which are not found
+    /// without it.
+    pub static_site: Option<StaticSite>,
+    /// Create missing directories for uploads instead of answering 409.
+    pub create_dirs: bool,
+    /// Bounds on `multipart/form-data` uploads.
+    pub multipart_limits: PartLimits,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+}
+
+/// How long a connection may stall before it is closed.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Time for the complete request head, counted from its first byte.
+    pub header_read: Duration,
+    /// Longest silence while the body is being received.
+    pub body_read: Duration,
+    /// Bytes per second a body has to average after its first `body_read`,
+    /// so that a trickle of data can't hold the connection forever. Zero
+    /// turns the check off.
+    pub body_min_rate: usize,
+    /// Time a keep-alive connection may wait for its next request.
+    pub idle: Duration,
+    /// Longest time a single write to the client may block.
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read: Duration::from_secs(10),
+            body_read: Duration::from_secs(30),
+            body_min_rate: 1024,
+            idle: Duration::from_secs(60),
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+pub async fn run_server(listener: TcpListener, config: Config) {
     let mut state_builder = State::builder().encoding("gzip".to_string(), encoders::gzip_encoder);
 
     let mut router_builder = Router::builder()
+        .add_middleware(middleware::etag)
         .add_middleware(middleware::content_encoding)
         .add_middleware(middleware::content_length)
         .exact_route("/", Method::GET, handlers::ok_handler)
         .exact_route("/user-agent", Method::GET, handlers::user_agent_handler)
-        .starts_with_route("/echo/", Method::GET, handlers::echo_handler);
-    if let Some(dir) = file_directory {
+        .route("/echo/*text", Method::GET, handlers::echo_handler);
+    if let Some(dir) = config.file_dir.clone() {
         router_builder = router_builder
-            .starts_with_route("/files/", Method::GET, handlers::file_get_handler)
-            .starts_with_route("/files/", Method::POST, handlers::file_post_handler);
-        state_builder = state_builder.file_dir(dir);
+            .route("/files/*path", Method::GET, handlers::file_get_handler)
+            .route("/files/*path", Method::POST, handlers::file_post_handler)
+            .route("/files/*path", Method::PUT, handlers::file_put_handler)
+            .route(
+                "/files/*path",
+                Method::DELETE,
+                handlers::file_delete_handler,
+            );
+        state_builder = state_builder
+            .file_dir(dir)
+            .mime_types(config.mime_types.clone())
+            .static_site(config.static_site.clone())
+            .create_dirs(config.create_dirs)
+            .multipart_limits(config.multipart_limits.clone());
     }
     let router = router_builder.build();
     let state = state_builder.build();
@@ -99,8 +298,9 @@ pub async fn run_server(listener: TcpListener, file_directory: Option<String>) {
                 println!("Accepted connection from address: {}", addr);
                 let state = state.clone();
                 let router = router.clone();
+                let config = config.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, router, state).await {
+                    if let Err(e) = handle_client(stream, router, state, &config).await {
                         eprintln!("Error with handling client: {:?}", e);
                     };
                 });
@@ -110,65 +310,752 @@ pub async fn run_server(listener: TcpListener, file_directory: Option<String>) {
     }
 }
 
-struct Connection {
-    stream: BufWriter<TcpStream>,
-    parser: RequestParser,
+const WRITE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// What writing a response needs to know about the request it answers.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub method: Method,
+    pub version: Version,
+    /// Whether the client is fine with the connection staying open.
+    pub keep_alive: bool,
+}
+
+impl Exchange {
+    pub fn new(metadata: &Metadata) -> Self {
+        Self {
+            method: metadata.method.clone(),
+            version: metadata.version,
+            keep_alive: metadata.keep_alive(),
+        }
+    }
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Self {
+            method: Method::GET,
+            version: Version::Http11,
+            keep_alive: true,
+        }
+    }
+}
+
+struct Connection<S> {
+    stream: BufWriter<WriteHalf<S>>,
+    incoming: Option<Incoming<ReadHalf<S>>>,
+    /// Set while the body of the last request is read by its handler.
+    lent: Option<SharedIncoming<ReadHalf<S>>>,
+    /// Set once the head of the current request has been parsed.
+    exchange: Option<Exchange>,
+    write_timeout: Duration,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(stream: S) -> Self {
+        Self::with_config(stream, &Config::default())
+    }
+
+    pub fn with_config(stream: S, config: &Config) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let parser = RequestParser::with_limits(config.limits.clone()).timeouts(config.timeouts);
         Connection {
-            stream: BufWriter::new(stream),
-            parser: RequestParser::new(),
+            stream: BufWriter::new(writer),
+            incoming: Some(Incoming::new(reader, parser)),
+            lent: None,
+            exchange: None,
+            write_timeout: config.timeouts.write,
         }
     }
 
     pub async fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
-        self.stream.write_all(data).await?;
-        Ok(())
+        match tokio::time::timeout(self.write_timeout, self.stream.write_all(data)).await {
+            Ok(result) => result,
+            Err(_) => Err(write_timed_out()),
+        }
+    }
+
+    pub async fn flush(&mut self) -> Result<(), std::io::Error> {
+        match tokio::time::timeout(self.write_timeout, self.stream.flush()).await {
+            Ok(result) => result,
+            Err(_) => Err(write_timed_out()),
+        }
     }
 
-    pub async fn write_response(&mut self, response: Response) -> Result<(), std::io::Error> {
-        let start_line = format!("HTTP/1.1 {}\r\n", response.status);
+    /// Writes `response` as the answer to the request described by
+    /// `exchange`, returning whether the connection can be kept open.
+    ///
+    /// Responses to HEAD carry the same headers as for GET but no body, and
+    /// statuses that cannot have a body never get one.
+    /// The connection is closed if either side asked for it with
+    /// `Connection: close`, or if an HTTP/1.0 client can only tell where a
+    /// body of unknown length ends by the connection closing.
+    pub async fn write_response(
+        &mut self,
+        mut response: Response,
+        exchange: &Exchange,
+    ) -> Result<bool, std::io::Error> {
+        let mut headers = response.headers.take().unwrap_or_default();
+        if !response.status.allows_body() {
+            response.body = None;
+            headers.remove("Content-Length");
+            headers.remove("Transfer-Encoding");
+        }
+        let send_body = exchange.method != Method::HEAD;
+        let unknown_length = !headers.contains("Content-Length")
+            && matches!(&response.body, Some(body) if body.len().is_none());
+        let chunked = unknown_length && exchange.version == Version::Http11;
+
+        let keep_alive = exchange.keep_alive
+            && !headers.has_token("Connection", "close")
+            && !(unknown_length && !chunked && send_body);
+        if !keep_alive {
+            headers.insert("Connection".to_string(), "close".to_string());
+        } else if exchange.version == Version::Http10 {
+            headers.insert("Connection".to_string(), "keep-alive".to_string());
+        }
+        if chunked {
+            headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        }
+
+        let start_line = format!("{} {}\r\n", exchange.version, response.status);
         self.write(start_line.as_bytes()).await?;
-        if let Some(headers) = response.headers {
-            for (header, value) in &headers {
-                self.write(format!("{header}: {value}\r\n").as_bytes())
-                    .await?;
-            }
+        for (header, value) in &headers {
+            self.write(format!("{header}: {value}\r\n").as_bytes())
+                .await?;
         }
         self.write("\r\n".as_bytes()).await?;
-        match response.body {
-            Some(Body { data }) => {
-                self.write(&data).await?;
+        if send_body {
+            match response.body {
+                Some(Body::Full(data)) => self.write(&data).await?,
+                Some(Body::Stream { reader, .. }) if chunked => self.write_chunked(reader).await?,
+                Some(Body::Stream { reader, .. }) => self.write_stream(reader).await?,
+                None => {}
             }
-            None => {}
         }
-        self.stream.flush().await?;
+        self.flush().await?;
+        Ok(keep_alive)
+    }
+
+    async fn write_stream(&mut self, mut reader: BodyReader) -> Result<(), std::io::Error> {
+        let mut buf = vec![0u8; WRITE_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.write(&buf[..n]).await?;
+        }
+    }
+
+    async fn write_chunked(&mut self, mut reader: BodyReader) -> Result<(), std::io::Error> {
+        let mut buf = vec![0u8; WRITE_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            self.write(format!("{n:x}\r\n").as_bytes()).await?;
+            self.write(&buf[..n]).await?;
+            self.write(b"\r\n").await?;
+            // push each chunk out so slow producers still reach the client
+            self.flush().await?;
+        }
+        self.write(b"0\r\n\r\n").await
+    }
+
+    /// Answers a request that could not be parsed. The connection has to be
+    /// closed afterwards since the rest of the request is left unread.
+    ///
+    /// Once the request head is known, the answer is in its version and
+    /// leaves out the body for HEAD, like any other response.
+    pub async fn write_error(
+        &mut self,
+        status: StatusCode,
+        extra: Headers,
+    ) -> Result<(), std::io::Error> {
+        let mut response = Response::from_status(status);
+        let mut headers = response.headers.take().unwrap_or_default();
+        for (name, value) in &extra {
+            headers.insert(name.clone(), value.clone());
+        }
+        if let Some(len) = response.body.as_ref().and_then(Body::len) {
+            headers.insert("Content-Length".to_string(), len.to_string());
+        }
+        response.headers = Some(headers);
+        let exchange = Exchange {
+            keep_alive: false,
+            ..self.exchange.take().unwrap_or_default()
+        };
+        self.write_response(response, &exchange).await?;
         Ok(())
     }
 
-    pub async fn read_request(&mut self) -> Result<Option<Request>, RequestParserError> {
-        match self.parser.read_request(&mut self.stream).await {
-            Ok(request) => Ok(Some(request)),
+    /// Takes the read side back from the handler the last body was lent to.
+    fn incoming(&mut self) -> &mut Incoming<ReadHalf<S>> {
+        if let Some(lent) = self.lent.take() {
+            self.incoming = lent.lock().unwrap_or_else(PoisonError::into_inner).take();
+        }
+        self.incoming
+            .as_mut()
+            .expect("read side is only lent out while a request is handled")
+    }
+
+    /// The error that cut short reading the body of the request being
+    /// handled, if there was one.
+    pub fn take_body_error(&mut self) -> Option<RequestParserError> {
+        let lent = self.lent.as_ref()?;
+        let mut incoming = lent.lock().unwrap_or_else(PoisonError::into_inner);
+        incoming.as_mut()?.take_error()
+    }
+
+    /// Reads the next request, or `None` once the client is done with the
+    /// connection, either by closing it or by staying idle for too long.
+    ///
+    /// The body is not read here: the request gets a stream that reads it
+    /// from the connection as the handler consumes it. Whatever the handler
+    /// leaves unread is skipped by `finish_body`.
+    ///
+    /// A client sending `Expect: 100-continue` waits for permission before
+    /// sending the body. It only gets it once `router` has a handler for the
+    /// request and the body fits the limits, otherwise the request is
+    /// rejected without reading the body.
+    pub async fn read_request(
+        &mut self,
+        router: &Router,
+    ) -> Result<Option<Request>, RequestParserError> {
+        self.exchange = None;
+        let incoming = self.incoming();
+        let metadata = match incoming.read_metadata().await {
+            Ok(metadata) => metadata,
+            Err(RequestParserError::IdleTimeout) => return Ok(None),
             Err(e @ RequestParserError::Disconnect) => {
-                if self.parser.buffer_is_empty() {
+                if incoming.parser.buffer_is_empty() {
                     return Ok(None);
                 }
-                Err(e)
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        self.exchange = Some(Exchange::new(&metadata));
+        let framing = self.incoming().parser.framing(&metadata)?;
+
+        if let Some(expect) = metadata.headers.get("Expect") {
+            if !expect.eq_ignore_ascii_case("100-continue") {
+                return Err(RequestError::ExpectationFailed.into());
+            }
+            router
+                .check(&metadata.path, &metadata.method)
+                .map_err(RequestParserError::Rejected)?;
+            // HTTP/1.0 clients don't know about interim responses
+            if framing != Framing::None && metadata.version == Version::Http11 {
+                self.write(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                self.flush().await?;
+            }
+        }
+
+        let len = match framing {
+            Framing::None => return Ok(Some(Request::new(metadata, None))),
+            Framing::Length(0) => {
+                return Ok(Some(Request::new(metadata, Some(Body::Full(Vec::new())))))
             }
-            Err(e) => Err(e),
+            Framing::Length(length) => Some(length as u64),
+            Framing::Chunked => None,
+        };
+        let mut incoming = self.incoming.take();
+        if let Some(incoming) = incoming.as_mut() {
+            incoming.start_body(framing);
         }
+        let lent = Arc::new(Mutex::new(incoming));
+        self.lent = Some(lent.clone());
+        let stream = BodyStream::new(lent);
+        let trailers = stream.trailers();
+        let mut request = Request::new(metadata, Some(Body::from_reader(stream, len)));
+        request.trailers = trailers;
+        Ok(Some(request))
+    }
+
+    /// Skips the unread rest of the last request body, so the next request
+    /// can be read.
+    pub async fn finish_body(&mut self) -> Result<(), RequestParserError> {
+        if self.lent.is_none() {
+            return Ok(());
+        }
+        self.incoming().finish_body().await
     }
 }
 
-async fn handle_client(stream: TcpStream, router: Router, state: State) -> anyhow::Result<()> {
-    let mut conn = Connection::new(stream);
-    while let Some(request) = conn.read_request().await? {
+async fn handle_client<S>(
+    stream: S,
+    router: Router,
+    state: State,
+    config: &Config,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut conn = Connection::with_config(stream, config);
+    loop {
+        let request = match conn.read_request(&router).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                if let Some(status) = e.status() {
+                    conn.write_error(status, e.headers()).await?;
+                }
+                return Err(e.into());
+            }
+        };
         let state = state.clone();
+        let exchange = Exchange::new(&request.metadata);
         let response = router.handle(request, state).await;
-        conn.write_response(response).await?;
+        // a body that broke off makes whatever the handler answered moot
+        if let Some(e) = conn.take_body_error() {
+            if let Some(status) = e.status() {
+                conn.write_error(status, e.headers()).await?;
+            }
+            return Err(e.into());
+        }
+        if !conn.write_response(response, &exchange).await? {
+            break;
+        }
+        conn.finish_body().await?;
     }
 
     Ok(())
 }
+
+fn write_timed_out() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "write to client timed out")
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::http::header::Headers;
+
+    async fn written(response: Response, method: Method) -> Result<String, std::io::Error> {
+        let exchange = Exchange {
+            method,
+            ..Exchange::default()
+        };
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = Connection::new(server);
+        conn.write_response(response, &exchange).await?;
+        drop(conn);
+        let mut out = String::new();
+        let mut client = client;
+        client.read_to_string(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn stream_without_length_is_chunked() -> Result<(), std::io::Error> {
+        let body = Body::from_reader(&b"hello world"[..], None);
+        let response = Response::from_body(StatusCode::Ok, Headers::new(), body);
+        assert_eq!(
+            written(response, Method::GET).await?,
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nb\r\nhello world\r\n0\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_with_length_is_raw() -> Result<(), std::io::Error> {
+        let mut headers = Headers::new();
+        headers.insert("Content-Length".to_string(), "5".to_string());
+        let body = Body::from_reader(&b"hello"[..], Some(5));
+        let response = Response::from_body(StatusCode::Ok, headers, body);
+        assert_eq!(
+            written(response, Method::GET).await?,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn head_omits_body() -> Result<(), std::io::Error> {
+        let mut headers = Headers::new();
+        headers.insert("Content-Length".to_string(), "5".to_string());
+        let response = Response::from_data(StatusCode::Ok, headers, b"hello".to_vec());
+        assert_eq!(
+            written(response, Method::HEAD).await?,
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_exceeded_gets_status() -> Result<(), std::io::Error> {
+        let config = Config {
+            limits: Limits {
+                max_headers: 1,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let router = Router::builder()
+            .exact_route("/", Method::GET, handlers::ok_handler)
+            .build();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            handle_client(server, router, State::builder().build(), &config).await
+        });
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n")
+            .await?;
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        assert!(out.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(server.await.unwrap().is_err());
+        Ok(())
+    }
+
+    fn short_timeouts() -> Config {
+        Config {
+            timeouts: Timeouts {
+                header_read: Duration::from_millis(50),
+                body_read: Duration::from_millis(50),
+                body_min_rate: 1024,
+                idle: Duration::from_millis(50),
+                write: Duration::from_millis(50),
+            },
+            ..Config::default()
+        }
+    }
+
+    /// Runs `handle_client` against a duplex peer that sends `input` and then
+    /// stalls, returning everything the server wrote before closing.
+    async fn stalled_peer(input: &[u8]) -> Result<String, std::io::Error> {
+        let config = short_timeouts();
+        let router = Router::builder()
+            .exact_route("/", Method::GET, handlers::ok_handler)
+            .exact_route("/", Method::POST, handlers::ok_handler)
+            .build();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            handle_client(server, router, State::builder().build(), &config).await
+        });
+
+        client.write_all(input).await?;
+        let mut out = String::new();
+        let read = client.read_to_string(&mut out);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("server did not close the connection")?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn header_read_timeout() -> Result<(), std::io::Error> {
+        let out = stalled_peer(b"GET / HTTP/1.1\r\nHost: local").await?;
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn body_read_timeout() -> Result<(), std::io::Error> {
+        let mut client = upload_server(short_timeouts());
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .await?;
+        let mut out = String::new();
+        let read = client.read_to_string(&mut out);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("server did not close the connection")?;
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn body_min_rate() -> Result<(), std::io::Error> {
+        let mut client = upload_server(short_timeouts());
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 50\r\n\r\n")
+            .await?;
+        // each byte arrives well within `body_read`, but far too slowly
+        for _ in 0..50 {
+            if client.write_all(b"a").await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut out = String::new();
+        let read = client.read_to_string(&mut out);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("server did not close the connection")?;
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unread_body_is_skipped() -> Result<(), std::io::Error> {
+        let out = conversation(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+            GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unread_body_that_stalls_closes() -> Result<(), std::io::Error> {
+        // the answer goes out before the body, but the connection can't be reused
+        let out = stalled_peer(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").await?;
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!out.contains("408"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_closes_quietly() -> Result<(), std::io::Error> {
+        let out = stalled_peer(b"GET / HTTP/1.1\r\n\r\n").await?;
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!out.contains("408"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_timeout() {
+        // the peer never reads, so the small pipe fills up
+        let (_client, server) = tokio::io::duplex(16);
+        let mut conn = Connection::with_config(server, &short_timeouts());
+        let response = Response::from_data(StatusCode::Ok, Headers::new(), vec![0; 64 * 1024]);
+        let result = conn.write_response(response, &Exchange::default()).await;
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::TimedOut)
+        );
+    }
+
+    /// Sends `input` in one go and closes the write half, returning
+    /// everything the server answered.
+    async fn conversation(input: &[u8]) -> Result<String, std::io::Error> {
+        let router = Router::builder()
+            .exact_route("/", Method::GET, handlers::ok_handler)
+            .exact_route("/", Method::POST, handlers::ok_handler)
+            .build();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            handle_client(server, router, State::builder().build(), &short_timeouts()).await
+        });
+
+        client.write_all(input).await?;
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn connection_close() -> Result<(), std::io::Error> {
+        let out =
+            conversation(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+                .await?;
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(out.contains("Connection: close\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http10_keep_alive() -> Result<(), std::io::Error> {
+        let out = conversation(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n").await?;
+        assert_eq!(out.matches("HTTP/1.0 200 OK").count(), 1);
+        assert!(out.contains("Connection: close\r\n"));
+
+        let out =
+            conversation(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n")
+                .await?;
+        assert_eq!(out.matches("HTTP/1.0 200 OK").count(), 2);
+        assert!(out.contains("Connection: keep-alive\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_in_request_version() -> Result<(), std::io::Error> {
+        let out = conversation(b"POST / HTTP/1.0\r\nContent-Length: x\r\n\r\n").await?;
+        assert!(out.starts_with("HTTP/1.0 400 Bad Request\r\n"), "{out}");
+        assert!(out.contains("Connection: close\r\n"));
+
+        // HEAD gets the headers of the error but not its body
+        let out = conversation(b"HEAD / HTTP/1.1\r\nContent-Length: x\r\n\r\n").await?;
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{out}");
+        assert!(out.ends_with("\r\n\r\n"), "{out}");
+
+        // without a request line there is no version to go by
+        let out = conversation(b"GET / HTTP/1.0 x\r\n\r\n").await?;
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{out}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http10_stream_is_close_delimited() -> Result<(), std::io::Error> {
+        let exchange = Exchange {
+            version: Version::Http10,
+            ..Exchange::default()
+        };
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = Connection::new(server);
+        let body = Body::from_reader(&b"hello"[..], None);
+        let response = Response::from_body(StatusCode::Ok, Headers::new(), body);
+        assert!(!conn.write_response(response, &exchange).await?);
+        drop(conn);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        assert_eq!(out, "HTTP/1.0 200 OK\r\nConnection: close\r\n\r\nhello");
+        Ok(())
+    }
+
+    /// Runs `handle_client` with a route that echoes request bodies.
+    fn upload_server(config: Config) -> tokio::io::DuplexStream {
+        fn echo_body(mut request: Request, _state: State) -> router::BoxResponseFuture {
+            Box::pin(async move {
+                let data = match request.body.take() {
+                    Some(body) => body.into_bytes().await.unwrap_or_default(),
+                    None => Vec::new(),
+                };
+                let mut headers = Headers::new();
+                headers.insert("Content-Length".to_string(), data.len().to_string());
+                Response::from_data(StatusCode::Ok, headers, data)
+            })
+        }
+
+        // answers with the `Checksum` trailer, which follows the body
+        fn echo_checksum(mut request: Request, _state: State) -> router::BoxResponseFuture {
+            Box::pin(async move {
+                let _ = request.bytes().await;
+                let checksum = request
+                    .trailers()
+                    .and_then(|trailers| trailers.get("Checksum").map(str::to_string))
+                    .unwrap_or_default();
+                Response::builder().text(checksum)
+            })
+        }
+
+        let router = Router::builder()
+            .exact_route("/upload", Method::POST, echo_body)
+            .exact_route("/checksum", Method::POST, echo_checksum)
+            .build();
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            handle_client(server, router, State::builder().build(), &config).await
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn chunked_trailers() -> Result<(), std::io::Error> {
+        let mut client = upload_server(short_timeouts());
+        client
+            .write_all(
+                b"POST /checksum HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                5\r\nhello\r\n0\r\nChecksum: abc\r\n\r\n",
+            )
+            .await?;
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\nabc"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expect_continue() -> Result<(), std::io::Error> {
+        let mut client = upload_server(short_timeouts());
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).await?;
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        client.write_all(b"hello").await?;
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\nhello"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_uploads() -> Result<(), std::io::Error> {
+        let mut client = upload_server(short_timeouts());
+        let chunk = vec![b'x'; 40 * 1024];
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+            .await?;
+        for _ in 0..4 {
+            client
+                .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                .await?;
+            client.write_all(&chunk).await?;
+            client.write_all(b"\r\n").await?;
+        }
+        client
+            .write_all(b"0\r\n\r\nPOST /upload HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .await?;
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        assert!(out.contains("Content-Length: 163840\r\n"));
+        assert!(out.ends_with("\r\n\r\nok"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broken_body_replaces_response() -> Result<(), std::io::Error> {
+        let mut client = upload_server(short_timeouts());
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n")
+            .await?;
+        let mut out = String::new();
+        client.read_to_string(&mut out).await?;
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{out}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expect_rejected_early() -> Result<(), std::io::Error> {
+        let config = Config {
+            limits: Limits {
+                max_body: 4,
+                ..Limits::default()
+            },
+            ..short_timeouts()
+        };
+        let cases: [(&[u8], &str); 3] = [
+            (
+                b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+                "HTTP/1.1 413 Content Too Large\r\n",
+            ),
+            (
+                b"POST /missing HTTP/1.1\r\nContent-Length: 1\r\nExpect: 100-continue\r\n\r\n",
+                "HTTP/1.1 404 Not Found\r\n",
+            ),
+            (
+                b"POST /upload HTTP/1.1\r\nContent-Length: 1\r\nExpect: teapot\r\n\r\n",
+                "HTTP/1.1 417 Expectation Failed\r\n",
+            ),
+        ];
+        for (input, status_line) in cases {
+            let mut client = upload_server(config.clone());
+            client.write_all(input).await?;
+            let mut out = String::new();
+            client.read_to_string(&mut out).await?;
+            assert!(out.starts_with(status_line), "{out}");
+            assert!(!out.contains("100 Continue"));
+        }
+        Ok(())
+    }
+}
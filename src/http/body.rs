@@ -0,0 +1,71 @@
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub type BodyReader = Pin<Box<dyn AsyncRead + Send + Sync + 'static>>;
+
+pub enum Body {
+    /// Body that is fully held in memory.
+    Full(Vec<u8>),
+
+    /// Body that is produced by a reader while it is written out. Without a
+    /// known `len` the response is sent with `Transfer-Encoding: chunked`.
+    Stream {
+        reader: BodyReader,
+        len: Option<u64>,
+    },
+}
+
+impl Body {
+    pub fn from_reader<R>(reader: R, len: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        Body::Stream {
+            reader: Box::pin(reader),
+            len,
+        }
+    }
+
+    /// Length of the body in bytes, if it is known up front.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Full(data) => Some(data.len() as u64),
+            Body::Stream { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Body::Stream { .. })
+    }
+
+    /// Reader over the body, whether it is held in memory or not.
+    pub fn into_reader(self) -> BodyReader {
+        match self {
+            Body::Full(data) => Box::pin(std::io::Cursor::new(data)),
+            Body::Stream { reader, .. } => reader,
+        }
+    }
+
+    /// Collects the whole body into memory.
+    pub async fn into_bytes(self) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Body::Full(data) => Ok(data),
+            Body::Stream { mut reader, len } => {
+                let mut data = Vec::with_capacity(len.unwrap_or(0).min(64 * 1024) as usize);
+                reader.read_to_end(&mut data).await?;
+                Ok(data)
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(data: Vec<u8>) -> Self {
+        Body::Full(data)
+    }
+}
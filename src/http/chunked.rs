@@ -0,0 +1,279 @@
+use bytes::{Buf, BytesMut};
+use std::{io::Cursor, str};
+use thiserror::Error;
+
+use crate::http::header::Headers;
+use crate::http::helpers::{self, CursorError};
+
+// 16 hex digits already cover a u64, anything longer is garbage
+const MAX_CHUNK_SIZE_DIGITS: usize = 16;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ChunkedError {
+    #[error("invalid chunk size line")]
+    InvalidSize,
+
+    #[error("chunk data not terminated by CRLF")]
+    MissingCrlf,
+
+    #[error("invalid trailer field")]
+    InvalidTrailer,
+
+    #[error("trailer fields too large")]
+    TrailersTooLarge,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Incremental decoder for `Transfer-Encoding: chunked` bodies.
+///
+/// Data is consumed from the front of the parser buffer as it becomes
+/// available, so a body can be decoded across any number of reads.
+pub struct ChunkedDecoder {
+    state: State,
+    trailers: Headers,
+    trailer_bytes: usize,
+    max_trailer_bytes: usize,
+    max_trailers: usize,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self::with_limits(16 * 1024, 100)
+    }
+
+    /// Decoder that gives up on trailer sections of more than
+    /// `max_trailer_bytes`, line breaks included, or `max_trailers` fields.
+    pub fn with_limits(max_trailer_bytes: usize, max_trailers: usize) -> Self {
+        Self {
+            state: State::Size,
+            trailers: Headers::new(),
+            trailer_bytes: 0,
+            max_trailer_bytes,
+            max_trailers,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn into_trailers(self) -> Headers {
+        self.trailers
+    }
+
+    /// Decodes as much of `buf` as possible, appending chunk data to `out`.
+    ///
+    /// Returns `Ok(true)` once the last chunk and the trailer section have
+    /// been consumed, `Ok(false)` if more data is needed.
+    pub fn decode(&mut self, buf: &mut BytesMut, out: &mut Vec<u8>) -> Result<bool, ChunkedError> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let (size, consumed) = match parse_size_line(buf)? {
+                        Some(parsed) => parsed,
+                        None => return Ok(false),
+                    };
+                    buf.advance(consumed);
+                    self.state = match size {
+                        0 => State::Trailers,
+                        n => State::Data(n),
+                    };
+                }
+                State::Data(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(false);
+                    }
+                    let n = remaining.min(buf.len());
+                    out.extend_from_slice(&buf[..n]);
+                    buf.advance(n);
+                    self.state = match remaining - n {
+                        0 => State::DataEnd,
+                        left => State::Data(left),
+                    };
+                }
+                State::DataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(ChunkedError::MissingCrlf);
+                    }
+                    buf.advance(2);
+                    self.state = State::Size;
+                }
+                State::Trailers => {
+                    let mut cursor = Cursor::new(&buf[..]);
+                    let line = match helpers::get_until_crlf(&mut cursor) {
+                        Ok(line) => line,
+                        // an unterminated line must not grow the buffer forever
+                        Err(CursorError::Incomplete)
+                            if self.trailer_bytes + buf.len() > self.max_trailer_bytes =>
+                        {
+                            return Err(ChunkedError::TrailersTooLarge)
+                        }
+                        Err(CursorError::Incomplete) => return Ok(false),
+                        Err(CursorError::Invalid) => return Err(ChunkedError::InvalidTrailer),
+                    };
+                    self.trailer_bytes += cursor.position() as usize;
+                    if self.trailer_bytes > self.max_trailer_bytes
+                        || self.trailers.len() >= self.max_trailers && !line.is_empty()
+                    {
+                        return Err(ChunkedError::TrailersTooLarge);
+                    }
+                    if line.is_empty() {
+                        self.state = State::Done;
+                    } else {
+                        let line =
+                            str::from_utf8(line).map_err(|_| ChunkedError::InvalidTrailer)?;
+                        self.trailers
+                            .insert_header_line(line)
+                            .map_err(|_| ChunkedError::InvalidTrailer)?;
+                    }
+                    let consumed = cursor.position() as usize;
+                    buf.advance(consumed);
+                }
+                State::Done => return Ok(true),
+            }
+        }
+    }
+}
+
+/// Parses `chunk-size [ ; chunk-ext ] CRLF`, returning the size and the
+/// number of bytes the line took up. Extensions are accepted and ignored.
+fn parse_size_line(buf: &[u8]) -> Result<Option<(usize, usize)>, ChunkedError> {
+    let mut cursor = Cursor::new(buf);
+    let line = match helpers::get_until_crlf(&mut cursor) {
+        Ok(line) => line,
+        Err(CursorError::Incomplete) => {
+            // a size line never gets this long, don't wait for more data
+            if buf.len() > 1024 {
+                return Err(ChunkedError::InvalidSize);
+            }
+            return Ok(None);
+        }
+        Err(CursorError::Invalid) => return Err(ChunkedError::InvalidSize),
+    };
+    let line = str::from_utf8(line).map_err(|_| ChunkedError::InvalidSize)?;
+    let size = match line.split_once(';') {
+        Some((size, _extensions)) => size,
+        None => line,
+    }
+    .trim_end_matches([' ', '\t']);
+
+    if size.is_empty()
+        || size.len() > MAX_CHUNK_SIZE_DIGITS
+        || !size.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(ChunkedError::InvalidSize);
+    }
+    let size = usize::from_str_radix(size, 16).map_err(|_| ChunkedError::InvalidSize)?;
+    Ok(Some((size, cursor.position() as usize)))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn decode_all(input: &[u8]) -> Result<(Vec<u8>, ChunkedDecoder), ChunkedError> {
+        let mut decoder = ChunkedDecoder::new();
+        let mut buf = BytesMut::from(input);
+        let mut out = Vec::new();
+        decoder.decode(&mut buf, &mut out)?;
+        Ok((out, decoder))
+    }
+
+    #[test]
+    fn single_read() -> Result<(), ChunkedError> {
+        let (out, decoder) = decode_all(b"4\r\nWiki\r\n6;name=val\r\npedia \r\n0\r\n\r\n")?;
+        assert!(decoder.is_done());
+        assert_eq!(out, b"Wikipedia ");
+        Ok(())
+    }
+
+    #[test]
+    fn byte_by_byte() -> Result<(), ChunkedError> {
+        let input = b"a\r\n0123456789\r\n0\r\nExpires: never\r\n\r\nGET";
+        let mut decoder = ChunkedDecoder::new();
+        let mut buf = BytesMut::new();
+        let mut out = Vec::new();
+        let mut done = false;
+        for byte in input {
+            buf.extend_from_slice(&[*byte]);
+            if decoder.decode(&mut buf, &mut out)? {
+                done = true;
+                break;
+            }
+        }
+        assert!(done);
+        assert_eq!(out, b"0123456789");
+        assert_eq!(decoder.into_trailers().get("Expires"), Some("never"));
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_next_request_in_buffer() -> Result<(), ChunkedError> {
+        let mut decoder = ChunkedDecoder::new();
+        let mut buf = BytesMut::from(&b"3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\n"[..]);
+        let mut out = Vec::new();
+        assert!(decoder.decode(&mut buf, &mut out)?);
+        assert_eq!(&buf[..], b"GET / HTTP/1.1\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_framing() {
+        assert_eq!(decode_all(b"zz\r\n").err(), Some(ChunkedError::InvalidSize));
+        assert_eq!(decode_all(b"\r\n").err(), Some(ChunkedError::InvalidSize));
+        assert_eq!(
+            decode_all(b"11111111111111111\r\n").err(),
+            Some(ChunkedError::InvalidSize)
+        );
+        assert_eq!(
+            decode_all(b"3\r\nabcd\r\n").err(),
+            Some(ChunkedError::MissingCrlf)
+        );
+        assert_eq!(
+            decode_all(b"0\r\nno-colon\r\n\r\n").err(),
+            Some(ChunkedError::InvalidTrailer)
+        );
+    }
+
+    #[test]
+    fn trailer_limits() {
+        let decode = |input: &[u8]| {
+            let mut decoder = ChunkedDecoder::with_limits(32, 2);
+            decoder.decode(&mut BytesMut::from(input), &mut Vec::new())
+        };
+        assert_eq!(decode(b"0\r\nA: 1\r\nB: 2\r\n\r\n"), Ok(true));
+        assert_eq!(
+            decode(b"0\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Err(ChunkedError::TrailersTooLarge)
+        );
+        assert_eq!(
+            decode(b"0\r\nA: 0123456789012345678901234567\r\n\r\n"),
+            Err(ChunkedError::TrailersTooLarge)
+        );
+        // without a line break in sight there is no point in waiting
+        assert_eq!(
+            decode(b"0\r\nA: 01234567890123456789012345678901234"),
+            Err(ChunkedError::TrailersTooLarge)
+        );
+        assert_eq!(decode(b"0\r\nA: 0123"), Ok(false));
+    }
+}
@@ -0,0 +1,199 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::header::Headers;
+use crate::http::helpers;
+use crate::http::response::Response;
+use crate::http::status::StatusCode;
+
+/// Validators of the current representation of a resource, used to answer
+/// conditional requests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Strong validators for a file, derived from its size and modification
+    /// time, so they change whenever the content is rewritten.
+    pub fn for_file(metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let stamp = modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        Self {
+            etag: Some(format!("\"{:x}-{:x}\"", metadata.len(), stamp)),
+            last_modified: modified,
+        }
+    }
+
+    /// Weak validator for a body generated on the fly.
+    pub fn for_data(data: &[u8]) -> Self {
+        Self {
+            etag: Some(weak_etag(data)),
+            last_modified: None,
+        }
+    }
+
+    /// Adds `ETag` and `Last-Modified` to `headers`.
+    pub fn apply(&self, headers: &mut Headers) {
+        if let Some(etag) = &self.etag {
+            headers.insert("ETag".to_string(), etag.clone());
+        }
+        if let Some(time) = self.last_modified {
+            headers.insert("Last-Modified".to_string(), helpers::http_date(time));
+        }
+    }
+
+    /// Whether a GET or HEAD with the request `headers` can be answered with
+    /// `304 Not Modified`. `If-Modified-Since` only counts without
+    /// `If-None-Match`.
+    pub fn not_modified(&self, headers: &Headers) -> bool {
+        if headers.contains("If-None-Match") {
+            return headers
+                .get_all("If-None-Match")
+                .any(|list| matches_any(list, self.etag.as_deref(), false));
+        }
+
+        let since = headers
+            .get("If-Modified-Since")
+            .and_then(helpers::parse_http_date);
+        match (since, self.last_modified) {
+            // the header only has second precision
+            (Some(since), Some(modified)) => truncate(modified) <= since,
+            _ => false,
+        }
+    }
+
+    /// `304 Not Modified` carrying these validators.
+    pub fn not_modified_response(&self) -> Response {
+        let mut response = Response::from_status(StatusCode::NotModified);
+        self.apply(response.headers.get_or_insert_with(Headers::new));
+        response
+    }
+}
+
+/// Whether a request with `headers` may replace the resource whose current
+/// tag is `etag`, or create it if `etag` is `None`.
+///
+/// `If-Match` needs a strong match, so `*` only passes for an existing
+/// resource. `If-None-Match` fails on any match, so `*` only passes if
+/// there is nothing yet.
+pub fn write_allowed(headers: &Headers, etag: Option<&str>) -> bool {
+    if headers.contains("If-Match")
+        && !headers
+            .get_all("If-Match")
+            .any(|list| matches_any(list, etag, true))
+    {
+        return false;
+    }
+    !headers
+        .get_all("If-None-Match")
+        .any(|list| matches_any(list, etag, false))
+}
+
+/// Whether the `If-Match` or `If-None-Match` value `list` matches `etag`,
+/// the tag of the current representation or `None` if there is none.
+///
+/// `*` matches any existing representation. Weak comparison ignores the
+/// `W/` prefix, strong comparison never matches weak tags.
+pub fn matches_any(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    if strong && etag.starts_with("W/") {
+        return false;
+    }
+    let opaque = etag.trim_start_matches("W/");
+    list.split(',').map(str::trim).any(|candidate| {
+        if strong {
+            candidate == etag
+        } else {
+            candidate.trim_start_matches("W/") == opaque
+        }
+    })
+}
+
+/// `W/"…"` tag from a 64 bit FNV-1a hash of `data`, which stays the same
+/// across restarts.
+pub fn weak_etag(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("W/\"{hash:016x}\"")
+}
+
+fn truncate(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn request(name: &str, value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.insert(name.to_string(), value.to_string());
+        headers
+    }
+
+    #[test]
+    fn etag_matching() {
+        assert!(matches_any("\"a\", \"b\"", Some("\"b\""), true));
+        assert!(matches_any("W/\"b\"", Some("\"b\""), false));
+        assert!(!matches_any("W/\"b\"", Some("\"b\""), true));
+        assert!(!matches_any("\"b\"", Some("W/\"b\""), true));
+        assert!(matches_any("*", Some("\"b\""), true));
+        assert!(!matches_any("*", None, false));
+        assert_eq!(weak_etag(b"hello"), weak_etag(b"hello"));
+        assert_ne!(weak_etag(b"hello"), weak_etag(b"hellp"));
+    }
+
+    #[test]
+    fn write_preconditions() {
+        let etag = Some("\"v1\"");
+        assert!(write_allowed(&Headers::new(), etag));
+        assert!(write_allowed(&request("If-Match", "\"v1\""), etag));
+        assert!(!write_allowed(&request("If-Match", "\"v0\""), etag));
+        assert!(!write_allowed(&request("If-Match", "*"), None));
+        assert!(write_allowed(&request("If-None-Match", "*"), None));
+        assert!(!write_allowed(&request("If-None-Match", "*"), etag));
+        assert!(write_allowed(&request("If-None-Match", "\"v0\""), etag));
+    }
+
+    #[test]
+    fn not_modified() {
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some(modified),
+        };
+        assert!(validators.not_modified(&request("If-None-Match", "\"v0\", W/\"v1\"")));
+        assert!(!validators.not_modified(&request("If-None-Match", "\"v0\"")));
+        assert!(validators.not_modified(&request(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        )));
+        assert!(!validators.not_modified(&request(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 08:49:36 GMT"
+        )));
+        assert!(!validators.not_modified(&request("If-Modified-Since", "yesterday")));
+
+        // If-None-Match wins over the date
+        let mut headers = request("If-None-Match", "\"v0\"");
+        headers.insert(
+            "If-Modified-Since".to_string(),
+            "Sun, 06 Nov 1994 08:49:37 GMT".to_string(),
+        );
+        assert!(!validators.not_modified(&headers));
+    }
+}
@@ -1,16 +1,99 @@
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use std::io::prelude::*;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
 
-use super::Body;
+use super::{Body, BodyReader};
 
 pub type EncoderFn = Box<dyn Fn(Body) -> Result<Body, anyhow::Error> + Send + Sync + 'static>;
 
 pub fn gzip_encoder(body: Body) -> Result<Body, anyhow::Error> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(&body.data)?;
-    encoder.flush()?;
-    Ok(Body {
-        data: encoder.finish()?,
-    })
+    match body {
+        Body::Full(data) => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            encoder.flush()?;
+            Ok(Body::Full(encoder.finish()?))
+        }
+        Body::Stream { reader, .. } => Ok(Body::from_reader(GzipReader::new(reader), None)),
+    }
+}
+
+/// Compresses a streaming body on the fly as it is read.
+pub struct GzipReader {
+    inner: BodyReader,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl GzipReader {
+    pub fn new(inner: BodyReader) -> Self {
+        Self {
+            inner,
+            encoder: Some(GzEncoder::new(Vec::new(), Compression::default())),
+            out: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for GzipReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.out.len() {
+                let n = buf.remaining().min(this.out.len() - this.pos);
+                buf.put_slice(&this.out[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let encoder = match this.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            let mut chunk = [0u8; 8 * 1024];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(this.inner.as_mut().poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                let encoder = this.encoder.take().unwrap();
+                this.out = encoder.finish()?;
+            } else {
+                encoder.write_all(chunk_buf.filled())?;
+                this.out = std::mem::take(encoder.get_mut());
+            }
+            this.pos = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn gzip_stream_roundtrip() -> Result<(), anyhow::Error> {
+        let input = b"streamed ".repeat(10_000);
+        let body = gzip_encoder(Body::from_reader(&b""[..], None))?;
+        assert!(!body.into_bytes().await?.is_empty());
+
+        let body = gzip_encoder(Body::from_reader(std::io::Cursor::new(input.clone()), None))?;
+        assert_eq!(body.len(), None);
+        let compressed = body.into_bytes().await?;
+        let mut decoded = Vec::new();
+        GzDecoder::new(&compressed[..]).read_to_end(&mut decoded)?;
+        assert_eq!(decoded, input);
+        Ok(())
+    }
 }
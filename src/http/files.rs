@@ -0,0 +1,407 @@
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+
+use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use super::range::ByteRange;
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error("path escapes the file directory")]
+    Forbidden,
+
+    #[error("path not found")]
+    NotFound,
+
+    #[error("io error while resolving path")]
+    Io(#[from] std::io::Error),
+}
+
+/// Turns the already percent-decoded path below a route prefix into a
+/// relative path made only of normal components.
+///
+/// `.` segments are dropped and `..` segments pop the previous one; popping
+/// past the start, absolute paths and anything the platform would not treat
+/// as a plain file name are rejected.
+pub fn normalize(decoded: &str) -> Result<PathBuf, ResolveError> {
+    if decoded.starts_with('/') || decoded.contains(['\\', '\0']) {
+        return Err(ResolveError::Forbidden);
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or(ResolveError::Forbidden)?;
+            }
+            segment => {
+                let mut components = Path::new(segment).components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(_)), None) => segments.push(segment),
+                    _ => return Err(ResolveError::Forbidden),
+                }
+            }
+        }
+    }
+    Ok(segments.iter().collect())
+}
+
+/// Resolves a request path against `root`, making sure the result cannot
+/// leave `root`, including through symlinks.
+///
+/// The returned path does not have to exist yet, so it can be used for
+/// uploads. Its closest existing ancestor is checked instead.
+pub async fn resolve(root: &Path, decoded: &str) -> Result<PathBuf, ResolveError> {
+    let relative = normalize(decoded)?;
+    let root = match fs::canonicalize(root).await {
+        Ok(root) => root,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(ResolveError::NotFound),
+        Err(e) => return Err(e.into()),
+    };
+    let path = root.join(relative);
+
+    let mut existing = path.as_path();
+    loop {
+        match fs::canonicalize(existing).await {
+            Ok(canonical) if canonical.starts_with(&root) => return Ok(path),
+            Ok(_) => return Err(ResolveError::Forbidden),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound || below_file(existing).await => {
+                // a dangling symlink would let a create follow it anywhere
+                if fs::symlink_metadata(existing).await.is_ok() {
+                    return Err(ResolveError::Forbidden);
+                }
+                existing = existing.parent().ok_or(ResolveError::NotFound)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Whether the closest existing ancestor of `path` is not a directory, so
+/// nothing can exist at `path`.
+pub(crate) async fn below_file(path: &Path) -> bool {
+    for ancestor in path.ancestors().skip(1) {
+        if let Ok(metadata) = fs::metadata(ancestor).await {
+            return !metadata.is_dir();
+        }
+    }
+    false
+}
+
+// how much of a range is read from the file at a time
+const RANGE_CHUNK: usize = 8 * 1024;
+
+/// Reads byte ranges of one open file in turn, interleaved with data that
+/// goes out as it is, e.g. the part headers of `multipart/byteranges`. The
+/// file is seeked to each range rather than opened again.
+pub struct RangesReader {
+    file: File,
+    segments: VecDeque<Segment>,
+    chunk: Vec<u8>,
+}
+
+enum Segment {
+    Data(Vec<u8>, usize),
+    Range(ByteRange),
+    /// Seek to the range started, with the bytes to read once it completes.
+    Seeking(u64),
+    Reading(u64),
+}
+
+impl RangesReader {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            segments: VecDeque::new(),
+            chunk: Vec::new(),
+        }
+    }
+
+    pub fn data(mut self, data: Vec<u8>) -> Self {
+        self.segments.push_back(Segment::Data(data, 0));
+        self
+    }
+
+    pub fn range(mut self, range: ByteRange) -> Self {
+        self.segments.push_back(Segment::Range(range));
+        self
+    }
+}
+
+impl AsyncRead for RangesReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let segment = match this.segments.front_mut() {
+                Some(segment) => segment,
+                None => return Poll::Ready(Ok(())),
+            };
+            match segment {
+                Segment::Data(data, pos) if *pos < data.len() => {
+                    let n = buf.remaining().min(data.len() - *pos);
+                    buf.put_slice(&data[*pos..*pos + n]);
+                    *pos += n;
+                    return Poll::Ready(Ok(()));
+                }
+                Segment::Range(range) => {
+                    let size = range.size();
+                    Pin::new(&mut this.file).start_seek(SeekFrom::Start(range.start))?;
+                    *segment = Segment::Seeking(size);
+                }
+                Segment::Seeking(size) => {
+                    ready!(Pin::new(&mut this.file).poll_complete(cx))?;
+                    *segment = Segment::Reading(*size);
+                }
+                Segment::Reading(remaining) if *remaining > 0 => {
+                    let n = (*remaining).min(buf.remaining().min(RANGE_CHUNK) as u64) as usize;
+                    this.chunk.resize(n, 0);
+                    let mut chunk = ReadBuf::new(&mut this.chunk);
+                    ready!(Pin::new(&mut this.file).poll_read(cx, &mut chunk))?;
+                    if chunk.filled().is_empty() {
+                        return Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "file ended before the range",
+                        )));
+                    }
+                    *remaining -= chunk.filled().len() as u64;
+                    buf.put_slice(chunk.filled());
+                    return Poll::Ready(Ok(()));
+                }
+                Segment::Data(..) | Segment::Reading(_) => {
+                    this.segments.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Hidden sibling of `path` to write to before moving it into place.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{name}.{}-{count}.part", std::process::id()))
+}
+
+/// Writes everything from `reader` to `path` through a temporary file that
+/// is renamed into place once complete, so nobody ever sees a partial file
+/// and a failed write leaves the old one intact.
+///
+/// With `create_only` an existing file is kept and `AlreadyExists` returned.
+/// Returns the metadata of the new file.
+pub async fn write_atomic<R>(
+    path: &Path,
+    reader: &mut R,
+    create_only: bool,
+) -> std::io::Result<std::fs::Metadata>
+where
+    R: AsyncRead + Unpin,
+{
+    stage(path, reader).await?.commit(create_only).await
+}
+
+/// A complete upload waiting in a temporary file next to its target, see
+/// [`stage`].
+pub struct Staged {
+    temp: PathBuf,
+    path: PathBuf,
+    metadata: std::fs::Metadata,
+}
+
+/// Writes everything from `reader` to a temporary file next to `path`,
+/// leaving `path` alone until the result is committed.
+pub async fn stage<R>(path: &Path, reader: &mut R) -> std::io::Result<Staged>
+where
+    R: AsyncRead + Unpin,
+{
+    let temp = temp_path(path);
+    let result = async {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .await?;
+        tokio::io::copy(reader, &mut file).await?;
+        file.sync_all().await?;
+        file.metadata().await
+    }
+    .await;
+
+    match result {
+        Ok(metadata) => Ok(Staged {
+            temp,
+            path: path.to_path_buf(),
+            metadata,
+        }),
+        Err(e) => {
+            let _ = fs::remove_file(&temp).await;
+            Err(e)
+        }
+    }
+}
+
+impl Staged {
+    /// Moves the file into place. With `create_only` an existing file is
+    /// kept and `AlreadyExists` returned. Returns the metadata of the new file.
+    pub async fn commit(self, create_only: bool) -> std::io::Result<std::fs::Metadata> {
+        let result = if create_only {
+            // unlike rename, linking fails if the target appeared meanwhile
+            fs::hard_link(&self.temp, &self.path).await
+        } else {
+            fs::rename(&self.temp, &self.path).await
+        };
+        if create_only || result.is_err() {
+            let _ = fs::remove_file(&self.temp).await;
+        }
+        result.map(|()| self.metadata)
+    }
+
+    /// Throws the upload away.
+    pub async fn discard(self) {
+        let _ = fs::remove_file(&self.temp).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::http::helpers;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("public")).unwrap();
+        root
+    }
+
+    #[test]
+    fn normalize_paths() -> Result<(), ResolveError> {
+        assert_eq!(normalize("a/b.txt")?, PathBuf::from("a/b.txt"));
+        assert_eq!(normalize("a/./b/../c.txt")?, PathBuf::from("a/c.txt"));
+        assert_eq!(normalize("a//b c")?, PathBuf::from("a/b c"));
+        assert_eq!(normalize("")?, PathBuf::new());
+        Ok(())
+    }
+
+    #[test]
+    fn traversal_shapes() {
+        let attacks = [
+            "../etc/passwd",
+            "a/../../etc/passwd",
+            "%2e%2e/etc/passwd",
+            "%2E%2E%2Fetc%2Fpasswd",
+            "..%2f..%2fetc/passwd",
+            "/etc/passwd",
+            "%2Fetc%2Fpasswd",
+            "..\\etc\\passwd",
+            "a%00.txt",
+        ];
+        for attack in attacks {
+            // paths reach the resolver decoded, as the request parser leaves them
+            let decoded = helpers::percent_decode(attack).unwrap();
+            assert!(
+                matches!(normalize(&decoded), Err(ResolveError::Forbidden)),
+                "{attack} was not rejected"
+            );
+        }
+        assert_eq!(helpers::percent_decode("%zz"), None);
+        assert_eq!(helpers::percent_decode("%c0%ae"), None);
+    }
+
+    #[tokio::test]
+    async fn resolve_inside_root() -> Result<(), ResolveError> {
+        let root = temp_root("resolve-inside");
+        std::fs::write(root.join("public/a.txt"), b"a").unwrap();
+        let public = root.join("public");
+        let canonical = std::fs::canonicalize(&public).unwrap();
+        assert_eq!(resolve(&public, "a.txt").await?, canonical.join("a.txt"));
+        assert_eq!(
+            resolve(&public, "new.txt").await?,
+            canonical.join("new.txt")
+        );
+        std::fs::remove_dir_all(root).unwrap();
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolve_symlink_escape() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_root("resolve-symlink");
+        std::fs::write(root.join("secret.txt"), b"secret").unwrap();
+        let public = root.join("public");
+        symlink(root.join("secret.txt"), public.join("link.txt")).unwrap();
+        symlink(&root, public.join("up")).unwrap();
+        symlink(root.join("missing.txt"), public.join("dangling.txt")).unwrap();
+
+        for path in ["link.txt", "up/secret.txt", "up/new.txt", "dangling.txt"] {
+            assert!(
+                matches!(resolve(&public, path).await, Err(ResolveError::Forbidden)),
+                "{path} was not rejected"
+            );
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ranges_of_one_handle() -> std::io::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let root = temp_root("ranges");
+        let path = root.join("public/a.txt");
+        std::fs::write(&path, b"0123456789")?;
+
+        let mut reader = RangesReader::new(File::open(&path).await?)
+            .range(ByteRange { start: 7, end: 9 })
+            .data(b"|".to_vec())
+            .range(ByteRange { start: 0, end: 1 });
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await?;
+        assert_eq!(out, b"789|01");
+
+        // the file shrinking under the reader is an error, not a short body
+        let mut reader =
+            RangesReader::new(File::open(&path).await?).range(ByteRange { start: 8, end: 20 });
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn atomic_writes() -> std::io::Result<()> {
+        let root = temp_root("atomic");
+        let path = root.join("public/a.txt");
+
+        write_atomic(&path, &mut &b"first"[..], true).await?;
+        let err = write_atomic(&path, &mut &b"second"[..], true).await;
+        assert_eq!(
+            err.map(|_| ()).map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(std::fs::read(&path)?, b"first");
+
+        let metadata = write_atomic(&path, &mut &b"third"[..], false).await?;
+        assert_eq!(metadata.len(), 5);
+        assert_eq!(std::fs::read(&path)?, b"third");
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(root.join("public"))?.count(), 1);
+        std::fs::remove_dir_all(root)
+    }
+}
@@ -1,18 +1,24 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::http::Body;
 use crate::http::{header::Headers, status::StatusCode};
+
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 
+use super::conditional::{self, Validators};
+use super::files::{self, RangesReader, ResolveError};
+use super::mime::{self, MimeTypes};
+use super::multipart::{self, MultipartError};
+use super::range::{self, ByteRange, Multipart, RangeOutcome};
 use super::router::BoxResponseFuture;
-use super::State;
+use super::site;
 use super::{request::Request, response::Response};
+use super::{Body, State};
 
 pub fn echo_handler(request: Request, _state: State) -> BoxResponseFuture {
     // error handling...
     Box::pin(async move {
-        let echo = request.metadata.path.strip_prefix("/echo/").unwrap();
+        let echo = request.param("text").unwrap_or("");
         let mut headers = Headers::new();
         headers.insert("Content-Type".to_string(), "text/plain".to_string());
         Response::from_data(StatusCode::Ok, headers, echo.as_bytes().to_vec())
@@ -35,6 +41,10 @@ pub fn method_not_allowed_handler(_request: Request, _state: State) -> BoxRespon
     Box::pin(async { Response::from_status(StatusCode::MethodNotAllowed) })
 }
 
+pub fn not_implemented_handler(_request: Request, _state: State) -> BoxResponseFuture {
+    Box::pin(async { Response::from_status(StatusCode::NotImplemented) })
+}
+
 pub fn user_agent_handler(request: Request, _state: State) -> BoxResponseFuture {
     Box::pin(async move {
         let user_agent = request.metadata.headers.get("User-Agent").unwrap_or("");
@@ -44,72 +54,878 @@ pub fn user_agent_handler(request: Request, _state: State) -> BoxResponseFuture
     })
 }
 
+/// Maps the `path` parameter of a `/files/*path` route into the file directory.
+async fn resolve_file_path(request: &Request, state: &State) -> Result<PathBuf, StatusCode> {
+    let file_path = request.param("path").ok_or(StatusCode::Internal)?;
+    resolve_in_file_dir(file_path, state).await
+}
+
+async fn resolve_in_file_dir(file_path: &str, state: &State) -> Result<PathBuf, StatusCode> {
+    let dir = state.file_dir().ok_or(StatusCode::Internal)?;
+    files::resolve(Path::new(dir), file_path)
+        .await
+        .map_err(|e| match e {
+            ResolveError::Forbidden => StatusCode::Forbidden,
+            ResolveError::NotFound => StatusCode::NotFound,
+            ResolveError::Io(_) => StatusCode::Internal,
+        })
+}
+
 pub fn file_get_handler(request: Request, state: State) -> BoxResponseFuture {
     Box::pin(async move {
-        let file_path = match request.metadata.path.strip_prefix("/files/") {
-            Some(path) => path,
-            None => return internal_error_handler(request, state).await,
+        let path = match resolve_file_path(&request, &state).await {
+            Ok(path) => path,
+            Err(status) => return Response::from_status(status),
         };
 
-        let dir = match state.file_dir() {
-            Some(dir) => dir,
-            None => return internal_error_handler(request, state).await,
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => directory_response(&request, &state, &path).await,
+            Ok(_) => file_response(&request, &state, &path).await,
+            Err(_) => Response::from_status(StatusCode::NotFound),
+        }
+    })
+}
+
+async fn file_response(request: &Request, state: &State, path: &Path) -> Response {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(_) => return Response::from_status(StatusCode::NotFound),
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Response::from_status(StatusCode::NotFound),
+        Err(_) => return Response::from_status(StatusCode::Internal),
+    };
+    let len = metadata.len();
+    let validators = Validators::for_file(&metadata);
+    if validators.not_modified(&request.metadata.headers) {
+        return validators.not_modified_response();
+    }
+
+    let content_type = match content_type(&mut file, path, state.mime_types()).await {
+        Ok(content_type) => content_type,
+        Err(_) => return Response::from_status(StatusCode::Internal),
+    };
+    let content_type = content_type.as_str();
+    let mut headers = Headers::new();
+    headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+    validators.apply(&mut headers);
+
+    match range::evaluate(request.metadata.headers.get("Range"), len) {
+        RangeOutcome::Full => {
+            headers.insert("Content-Type".to_string(), content_type.to_string());
+            Response::from_body(StatusCode::Ok, headers, Body::from_reader(file, Some(len)))
+        }
+        RangeOutcome::Unsatisfiable => {
+            let mut response = Response::from_status(StatusCode::RangeNotSatisfiable);
+            let response_headers = response.headers.get_or_insert_with(Headers::new);
+            response_headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+            response_headers.insert("Content-Range".to_string(), format!("bytes */{len}"));
+            response
+        }
+        RangeOutcome::Partial(ranges) => {
+            let body = match ranges_body(file, &ranges, content_type, len) {
+                (body, Some(multipart_type)) => {
+                    headers.insert("Content-Type".to_string(), multipart_type);
+                    body
+                }
+                (body, None) => {
+                    headers.insert("Content-Type".to_string(), content_type.to_string());
+                    headers.insert("Content-Range".to_string(), ranges[0].content_range(len));
+                    body
+                }
+            };
+            Response::from_body(StatusCode::PartialContent, headers, body)
+        }
+    }
+}
+
+/// Answers a request for a directory with its index file or a listing, if
+/// the static site settings allow either.
+async fn directory_response(request: &Request, state: &State, dir: &Path) -> Response {
+    let site = match state.static_site() {
+        Some(site) => site,
+        None => return Response::from_status(StatusCode::NotFound),
+    };
+
+    // relative links in the page only resolve inside the directory with a
+    // trailing slash
+    let raw_path = request.metadata.raw_path();
+    if !raw_path.ends_with('/') {
+        let mut location = format!("{raw_path}/");
+        if let Some(query) = request.metadata.raw_query() {
+            location.push('?');
+            location.push_str(query);
+        }
+        let mut response = Response::from_status(StatusCode::MovedPermanently);
+        let headers = response.headers.get_or_insert_with(Headers::new);
+        headers.insert("Location".to_string(), location);
+        return response;
+    }
+
+    // ends with a slash unless it is the file directory itself
+    let dir_path = request.param("path").unwrap_or("");
+    // resolved again, the index itself could be a symlink out of the directory
+    match resolve_in_file_dir(&format!("{dir_path}{}", site.index), state).await {
+        Ok(index) if tokio::fs::metadata(&index).await.is_ok_and(|m| m.is_file()) => {
+            return file_response(request, state, &index).await;
+        }
+        Ok(_) | Err(StatusCode::NotFound) => {}
+        Err(status) => return Response::from_status(status),
+    }
+    if !site.listing {
+        return Response::from_status(StatusCode::NotFound);
+    }
+
+    let entries = match site::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return Response::from_status(StatusCode::Internal),
+    };
+    if prefers_json(&request.metadata.headers) {
+        return Response::json_text(StatusCode::Ok, site::json(&entries));
+    }
+    let mut headers = Headers::new();
+    headers.insert(
+        "Content-Type".to_string(),
+        "text/html; charset=utf-8".to_string(),
+    );
+    let data = site::html(&request.metadata.path, &entries);
+    Response::from_data(StatusCode::Ok, headers, data.into_bytes())
+}
+
+/// Whether `Accept` asks for JSON rather than HTML.
+fn prefers_json(headers: &Headers) -> bool {
+    let accepted: Vec<String> = headers
+        .get_all("Accept")
+        .flat_map(|value| value.split(','))
+        .map(|item| {
+            item.split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .collect();
+    accepted.iter().any(|mime| mime == "application/json")
+        && !accepted.iter().any(|mime| mime == "text/html")
+}
+
+/// Works out the type of `file`, sniffing its first bytes if the extension
+/// doesn't tell and `mime_types` allows it.
+async fn content_type(
+    file: &mut File,
+    path: &Path,
+    mime_types: &MimeTypes,
+) -> Result<String, std::io::Error> {
+    if let Some(mime) = mime_types.lookup(path) {
+        return Ok(mime);
+    }
+    if !mime_types.sniffs() {
+        return Ok(mime_types.content_type(path, None));
+    }
+
+    let mut head = Vec::with_capacity(mime::SNIFF_LEN);
+    (&mut *file)
+        .take(mime::SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    file.rewind().await?;
+    Ok(mime_types.content_type(path, Some(&head)))
+}
+
+/// Streams the `ranges` of `file`. Several ranges go out as
+/// `multipart/byteranges`, whose content type is returned along with the body.
+fn ranges_body(
+    file: File,
+    ranges: &[ByteRange],
+    content_type: &str,
+    len: u64,
+) -> (Body, Option<String>) {
+    if let [range] = ranges {
+        let reader = RangesReader::new(file).range(*range);
+        return (Body::from_reader(reader, Some(range.size())), None);
+    }
+
+    let multipart = Multipart::new(content_type, len);
+    let mut reader = RangesReader::new(file);
+    for range in ranges {
+        reader = reader
+            .data(multipart.part_header(range).into_bytes())
+            .range(*range);
+    }
+    let reader = reader.data(multipart.closing().into_bytes());
+    let body = Body::from_reader(reader, Some(multipart.body_len(ranges)));
+    (body, Some(multipart.content_type()))
+}
+
+/// Stores the body as the file. A `multipart/form-data` body, as sent by
+/// browser forms, stores its file parts instead.
+pub fn file_post_handler(request: Request, state: State) -> BoxResponseFuture {
+    Box::pin(async move {
+        let media_type = request.metadata.media_type();
+        if media_type.is_some_and(|mime| mime.eq_ignore_ascii_case("multipart/form-data")) {
+            multipart_upload(request, state).await
+        } else {
+            upload(request, state).await
+        }
+    })
+}
+
+/// Replaces the file with the body. Same as POST, which uploads have
+/// always used.
+pub fn file_put_handler(request: Request, state: State) -> BoxResponseFuture {
+    Box::pin(upload(request, state))
+}
+
+async fn upload(mut request: Request, state: State) -> Response {
+    let path = match resolve_file_path(&request, &state).await {
+        Ok(path) => path,
+        Err(status) => return Response::from_status(status),
+    };
+    let mut reader = match request.body.take() {
+        Some(body) => body.into_reader(),
+        None => Box::pin(tokio::io::empty()),
+    };
+    store(&path, &request.metadata.headers, &mut reader, &state).await
+}
+
+/// Saves every file part into the directory the request names, or the
+/// first one as the file it names.
+async fn multipart_upload(mut request: Request, state: State) -> Response {
+    let path = match resolve_file_path(&request, &state).await {
+        Ok(path) => path,
+        Err(status) => return Response::from_status(status),
+    };
+    let mut multipart =
+        match multipart::Multipart::from_request(&mut request, state.multipart_limits().clone()) {
+            Ok(multipart) => multipart,
+            Err(e) => return Response::from_status(e.status()),
         };
+    if tokio::fs::metadata(&path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        let dir = request.param("path").unwrap_or("");
+        return store_parts(dir, &request.metadata.headers, multipart, &state).await;
+    }
+
+    loop {
+        match multipart.next_part().await {
+            Ok(Some(part)) if part.is_file() => break,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Response::from_status(StatusCode::BadRequest),
+            Err(e) => return Response::from_status(e.status()),
+        }
+    }
+    let mut reader = multipart.reader();
+    store(&path, &request.metadata.headers, &mut reader, &state).await
+}
+
+/// Writes each file part to `dir` under the last component of its file
+/// name, with the same checks as a single upload. Answers with each name
+/// and whether it was created or replaced.
+///
+/// The parts are staged first and only moved into place once all of them
+/// arrived, so a part that fails leaves every file as it was. Only a file
+/// created by someone else meanwhile can still stop it half way.
+async fn store_parts(
+    dir: &str,
+    headers: &Headers,
+    mut multipart: multipart::Multipart,
+    state: &State,
+) -> Response {
+    let mut staged = Vec::new();
+    let result = stage_parts(dir, headers, &mut multipart, state, &mut staged).await;
+    if let Err(status) = result {
+        for (_, _, upload) in staged {
+            upload.discard().await;
+        }
+        return Response::from_status(status);
+    }
+    if staged.is_empty() {
+        return Response::from_status(StatusCode::BadRequest);
+    }
+
+    let mut report = String::new();
+    let mut created = false;
+    let mut staged = staged.into_iter();
+    while let Some((name, existed, upload)) = staged.next() {
+        if let Err(e) = upload.commit(create_only(headers)).await {
+            for (_, _, upload) in staged {
+                upload.discard().await;
+            }
+            return Response::from_status(match e.kind() {
+                std::io::ErrorKind::AlreadyExists => StatusCode::PreconditionFailed,
+                _ => StatusCode::Internal,
+            });
+        }
+        created |= !existed;
+        let outcome = if existed { "replaced" } else { "created" };
+        report.push_str(&format!("{name} {outcome}\n"));
+    }
 
-        let path = Path::new(dir).join(file_path);
+    let mut headers = Headers::new();
+    headers.insert("Content-Type".to_string(), "text/plain".to_string());
+    let status = if created {
+        StatusCode::Created
+    } else {
+        StatusCode::Ok
+    };
+    Response::from_data(status, headers, report.into_bytes())
+}
 
-        let mut file = match File::open(path).await {
-            Ok(file) => file,
-            Err(_) => return not_found_handler(request, state).await,
+/// Stages the file parts for [`store_parts`], adding each with its name and
+/// whether it replaces a file to `staged`.
+async fn stage_parts(
+    dir: &str,
+    headers: &Headers,
+    multipart: &mut multipart::Multipart,
+    state: &State,
+    staged: &mut Vec<(String, bool, files::Staged)>,
+) -> Result<(), StatusCode> {
+    while let Some(part) = multipart.next_part().await.map_err(|e| e.status())? {
+        let name = match part.filename.as_deref().and_then(upload_name) {
+            Some(name) => name.to_string(),
+            None => continue,
         };
-        let mut buf = Vec::new();
-        match file.read_to_end(&mut buf).await {
-            Ok(_) => {},
-            Err(_) => return internal_error_handler(request, state).await,
+        let file_path = match dir.trim_end_matches('/') {
+            "" => name.clone(),
+            dir => format!("{dir}/{name}"),
         };
+        let path = resolve_in_file_dir(&file_path, state).await?;
+        let current = check_target(&path, headers).await?;
+        let upload = files::stage(&path, &mut multipart.reader())
+            .await
+            .map_err(|e| write_error_status(&e))?;
+        staged.push((name, current.is_some(), upload));
+    }
+    Ok(())
+}
 
-        let mut headers = Headers::new();
-        headers.insert(
-            "Content-Type".to_string(),
-            "application/octet-stream".to_string(),
-        );
+/// File name to store an uploaded file under. Browsers may send a full
+/// client path, only its last component is kept.
+fn upload_name(filename: &str) -> Option<&str> {
+    let name = filename.rsplit(['/', '\\']).next()?;
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name),
+    }
+}
 
-        Response::from_data(StatusCode::Ok, headers, buf)
-    })
+fn write_error_status(err: &std::io::Error) -> StatusCode {
+    match MultipartError::from_io(err) {
+        Some(e) => e.status(),
+        None => StatusCode::Internal,
+    }
+}
+
+/// Writes `reader` to the file at `path` if the preconditions in `headers`
+/// hold.
+async fn store<R>(path: &Path, headers: &Headers, reader: &mut R, state: &State) -> Response
+where
+    R: AsyncRead + Unpin,
+{
+    let current = match check_target(path, headers).await {
+        Ok(current) => current,
+        Err(status) => return Response::from_status(status),
+    };
+
+    // only now that the upload may go ahead
+    if let Err(status) = prepare_parent(path, state.create_dirs()).await {
+        return Response::from_status(status);
+    }
+    // the body is written as it arrives, never held in memory as a whole
+    let metadata = match files::write_atomic(path, reader, create_only(headers)).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Response::from_status(StatusCode::PreconditionFailed)
+        }
+        Err(e) => return Response::from_status(write_error_status(&e)),
+    };
+
+    let mut response = match current {
+        Some(_) => Response::from_status(StatusCode::NoContent),
+        None => Response::from_status(StatusCode::Created),
+    };
+    let headers = response.headers.get_or_insert_with(Headers::new);
+    Validators::for_file(&metadata).apply(headers);
+    response
+}
+
+/// Validators of the file an upload to `path` replaces, if there is one.
+/// Fails with 409 if something other than a file is in the way and with 412
+/// if the preconditions in `headers` don't hold.
+async fn check_target(path: &Path, headers: &Headers) -> Result<Option<Validators>, StatusCode> {
+    let current = match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => Some(Validators::for_file(&metadata)),
+        Ok(_) => return Err(StatusCode::Conflict),
+        // a file in place of a parent directory is up to `prepare_parent`
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound || files::below_file(path).await => None,
+        Err(_) => return Err(StatusCode::Internal),
+    };
+    let etag = current.as_ref().and_then(|current| current.etag.as_deref());
+    if !conditional::write_allowed(headers, etag) {
+        return Err(StatusCode::PreconditionFailed);
+    }
+    Ok(current)
+}
+
+/// Whether `headers` only allow creating a file, which still has to hold when
+/// it is written: another upload may create it after the check.
+fn create_only(headers: &Headers) -> bool {
+    headers
+        .get("If-None-Match")
+        .is_some_and(|v| v.trim() == "*")
+}
+
+/// Makes sure the directory `path` goes into exists, creating it if
+/// `create_dirs` allows. Anything else in the way is a conflict.
+async fn prepare_parent(path: &Path, create_dirs: bool) -> Result<(), StatusCode> {
+    let parent = path.parent().ok_or(StatusCode::Conflict)?;
+    match tokio::fs::metadata(parent).await {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && create_dirs => {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|_| StatusCode::Conflict)
+        }
+        _ => Err(StatusCode::Conflict),
+    }
 }
 
-pub fn file_post_handler(mut request: Request, state: State) -> BoxResponseFuture {
+pub fn file_delete_handler(request: Request, state: State) -> BoxResponseFuture {
     Box::pin(async move {
-        let file_path = match request.metadata.path.strip_prefix("/files/") {
-            Some(path) => path,
-            None => return internal_error_handler(request, state).await,
+        let path = match resolve_file_path(&request, &state).await {
+            Ok(path) => path,
+            Err(status) => return Response::from_status(status),
         };
 
-        let dir = match state.file_dir() {
-            Some(dir) => dir,
-            None => return internal_error_handler(request, state).await,
+        let current = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => Validators::for_file(&metadata),
+            Ok(_) => return Response::from_status(StatusCode::Conflict),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Response::from_status(StatusCode::NotFound)
+            }
+            Err(_) => return Response::from_status(StatusCode::Internal),
         };
+        if !conditional::write_allowed(&request.metadata.headers, current.etag.as_deref()) {
+            return Response::from_status(StatusCode::PreconditionFailed);
+        }
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Response::from_status(StatusCode::NoContent),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Response::from_status(StatusCode::NotFound)
+            }
+            Err(_) => Response::from_status(StatusCode::Internal),
+        }
+    })
+}
 
-        let path = Path::new(dir).join(file_path);
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
 
-        let mut file = match File::create(path).await {
-            Ok(file) => file,
-            Err(_) => return internal_error_handler(request, state).await,
-        };
+    use super::*;
+    use crate::http::middleware;
+    use crate::http::multipart::PartLimits;
+    use crate::http::request::Metadata;
+    use crate::http::site::StaticSite;
+    use crate::http::{Method, Version};
 
-        let data = match request.body.take() {
-            Some(Body { data }) => data,
-            _ => return internal_error_handler(request, state).await,
-        };
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("handlers-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn state(root: &Path) -> State {
+        State::builder()
+            .file_dir(root.to_string_lossy().into_owned())
+            .build()
+    }
 
-        if let Err(_) = file.write_all(&data).await {
-            return internal_error_handler(request, state).await;
+    /// Request for `/files/{target}` as the router hands it to the handlers.
+    fn request(method: Method, target: &str, headers: &[(&str, &str)], body: &[u8]) -> Request {
+        let mut map = Headers::new();
+        for (name, value) in headers {
+            map.insert(name.to_string(), value.to_string());
         }
+        let full_target = format!("/files/{target}");
+        let metadata = Metadata::from_target(method, Version::Http11, full_target, map).unwrap();
+        let mut request = Request::new(metadata, Some(Body::Full(body.to_vec())));
+        let path = target.split('?').next().unwrap_or("");
+        request.params.push("path", path);
+        request
+    }
+
+    fn get(target: &str, headers: &[(&str, &str)]) -> Request {
+        request(Method::GET, target, headers, b"")
+    }
+
+    fn header<'r>(response: &'r Response, name: &str) -> Option<&'r str> {
+        response.headers.as_ref()?.get(name)
+    }
 
-        if let Err(_) = file.flush().await {
-            return internal_error_handler(request, state).await;
+    async fn read_body(response: Response) -> Vec<u8> {
+        match response.body {
+            Some(body) => body.into_bytes().await.unwrap(),
+            None => Vec::new(),
         }
+    }
 
-        Response::from_status(StatusCode::Created)
-    })
+    /// Entries of `dir`, sorted by name.
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn put_and_post() -> std::io::Result<()> {
+        let root = temp_root("put");
+        let state = state(&root);
+
+        let put = request(Method::PUT, "a.txt", &[], b"first");
+        let response = file_put_handler(put, state.clone()).await;
+        assert_eq!(response.status, StatusCode::Created);
+        let etag = header(&response, "ETag").unwrap().to_string();
+        assert_eq!(std::fs::read(root.join("a.txt"))?, b"first");
+
+        let post = request(Method::POST, "a.txt", &[("If-Match", &etag)], b"second");
+        let response = file_post_handler(post, state.clone()).await;
+        assert_eq!(response.status, StatusCode::NoContent);
+        assert_eq!(std::fs::read(root.join("a.txt"))?, b"second");
+
+        // the etag changed with the content
+        let put = request(Method::PUT, "a.txt", &[("If-Match", &etag)], b"third");
+        let response = file_put_handler(put, state.clone()).await;
+        assert_eq!(response.status, StatusCode::PreconditionFailed);
+        let put = request(Method::PUT, "a.txt", &[("If-None-Match", "*")], b"third");
+        let response = file_put_handler(put, state.clone()).await;
+        assert_eq!(response.status, StatusCode::PreconditionFailed);
+        assert_eq!(std::fs::read(root.join("a.txt"))?, b"second");
+
+        std::fs::create_dir(root.join("dir"))?;
+        let put = request(Method::PUT, "dir", &[], b"data");
+        let response = file_put_handler(put, state.clone()).await;
+        assert_eq!(response.status, StatusCode::Conflict);
+        let put = request(Method::PUT, "a.txt/b.txt", &[], b"data");
+        let response = file_put_handler(put, state).await;
+        assert_eq!(response.status, StatusCode::Conflict);
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn delete() -> std::io::Result<()> {
+        let root = temp_root("delete");
+        let state = state(&root);
+        std::fs::write(root.join("a.txt"), b"a")?;
+        std::fs::create_dir(root.join("dir"))?;
+
+        let response =
+            file_delete_handler(request(Method::DELETE, "a.txt", &[], b""), state.clone()).await;
+        assert_eq!(response.status, StatusCode::NoContent);
+        assert!(!root.join("a.txt").exists());
+        let response =
+            file_delete_handler(request(Method::DELETE, "a.txt", &[], b""), state.clone()).await;
+        assert_eq!(response.status, StatusCode::NotFound);
+        let response = file_delete_handler(request(Method::DELETE, "dir", &[], b""), state).await;
+        assert_eq!(response.status, StatusCode::Conflict);
+        assert!(root.join("dir").is_dir());
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn missing_parents() -> std::io::Result<()> {
+        let root = temp_root("parents");
+        let put = request(Method::PUT, "a/b/c.txt", &[], b"data");
+        let response = file_put_handler(put, state(&root)).await;
+        assert_eq!(response.status, StatusCode::Conflict);
+        assert!(!root.join("a").exists());
+
+        let state = State::builder()
+            .file_dir(root.to_string_lossy().into_owned())
+            .create_dirs(true)
+            .build();
+        // a failed precondition creates nothing either
+        let put = request(Method::PUT, "a/b/c.txt", &[("If-Match", "\"x\"")], b"data");
+        let response = file_put_handler(put, state.clone()).await;
+        assert_eq!(response.status, StatusCode::PreconditionFailed);
+        assert!(!root.join("a").exists());
+
+        let put = request(Method::PUT, "a/b/c.txt", &[], b"data");
+        let response = file_put_handler(put, state).await;
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(std::fs::read(root.join("a/b/c.txt"))?, b"data");
+        std::fs::remove_dir_all(root)
+    }
+
+    /// Produces some data, then fails like a dropped connection.
+    struct Broken(bool);
+
+    impl AsyncRead for Broken {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if std::mem::replace(&mut self.0, true) {
+                let err = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "gone");
+                return std::task::Poll::Ready(Err(err));
+            }
+            buf.put_slice(b"partial");
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_write_leaves_nothing() -> std::io::Result<()> {
+        let root = temp_root("failed-write");
+        std::fs::write(root.join("a.txt"), b"old")?;
+        let mut put = request(Method::PUT, "a.txt", &[], b"");
+        put.body = Some(Body::from_reader(Broken(false), Some(100)));
+        let response = file_put_handler(put, state(&root)).await;
+        assert_eq!(response.status, StatusCode::Internal);
+        assert_eq!(std::fs::read(root.join("a.txt"))?, b"old");
+        assert_eq!(entries(&root), ["a.txt"]);
+        std::fs::remove_dir_all(root)
+    }
+
+    const FORM_TYPE: &str = "multipart/form-data; boundary=XyZ";
+
+    /// `multipart/form-data` body with a text field followed by the files
+    /// given as name and content.
+    fn form(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut body =
+            "--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n".to_string();
+        for (name, content) in files {
+            body.push_str(&format!(
+                "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\r\n{content}\r\n"
+            ));
+        }
+        body.push_str("--XyZ--\r\n");
+        body.into_bytes()
+    }
+
+    #[tokio::test]
+    async fn multipart_to_file() -> std::io::Result<()> {
+        let root = temp_root("multipart-file");
+        let body = form(&[("x.txt", "one"), ("y.txt", "two")]);
+        let post = request(Method::POST, "a.txt", &[("Content-Type", FORM_TYPE)], &body);
+        let response = file_post_handler(post, state(&root)).await;
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(std::fs::read(root.join("a.txt"))?, b"one");
+        assert_eq!(entries(&root), ["a.txt"]);
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn multipart_to_dir() -> std::io::Result<()> {
+        let root = temp_root("multipart-dir");
+        let state = state(&root);
+        std::fs::create_dir_all(root.join("up/sub"))?;
+        std::fs::write(root.join("up/old.txt"), b"old")?;
+
+        let body = form(&[("old.txt", "one"), ("/home/me/new.txt", "two")]);
+        let post = request(Method::POST, "up/", &[("Content-Type", FORM_TYPE)], &body);
+        let response = file_post_handler(post, state.clone()).await;
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(
+            String::from_utf8(read_body(response).await).unwrap(),
+            "old.txt replaced\nnew.txt created\n"
+        );
+        assert_eq!(std::fs::read(root.join("up/old.txt"))?, b"one");
+        assert_eq!(std::fs::read(root.join("up/new.txt"))?, b"two");
+
+        // every part is checked like a single upload, and nothing is stored
+        // unless all of them can be
+        let headers = [("Content-Type", FORM_TYPE), ("If-None-Match", "*")];
+        let body = form(&[("fresh.txt", "three"), ("old.txt", "three")]);
+        let response =
+            file_post_handler(request(Method::POST, "up", &headers, &body), state.clone()).await;
+        assert_eq!(response.status, StatusCode::PreconditionFailed);
+        let body = form(&[("fresh.txt", "three"), ("sub", "three")]);
+        let post = request(Method::POST, "up", &[("Content-Type", FORM_TYPE)], &body);
+        let response = file_post_handler(post, state).await;
+        assert_eq!(response.status, StatusCode::Conflict);
+        assert_eq!(entries(&root.join("up")), ["new.txt", "old.txt", "sub"]);
+        assert_eq!(std::fs::read(root.join("up/old.txt"))?, b"one");
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn multipart_rejected() -> std::io::Result<()> {
+        let root = temp_root("multipart-rejected");
+        let body = form(&[("x.txt", "one")]);
+        let headers = [("Content-Type", "multipart/form-data")];
+        let response = file_post_handler(
+            request(Method::POST, "a.txt", &headers, &body),
+            state(&root),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::BadRequest);
+        let headers = [("Content-Type", "text/plain")];
+        let response = multipart_upload(
+            request(Method::POST, "a.txt", &headers, &body),
+            state(&root),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::UnsupportedMediaType);
+        assert!(entries(&root).is_empty());
+
+        let state = State::builder()
+            .file_dir(root.to_string_lossy().into_owned())
+            .multipart_limits(PartLimits {
+                max_part_size: 4,
+                ..PartLimits::default()
+            })
+            .build();
+        // the part that fits is not kept either
+        let body = form(&[("a.txt", "abc"), ("b.txt", "too long")]);
+        let post = request(Method::POST, "", &[("Content-Type", FORM_TYPE)], &body);
+        let response = file_post_handler(post, state.clone()).await;
+        assert_eq!(response.status, StatusCode::ContentTooLarge);
+        let body = form(&[("b.txt", "too long")]);
+        let post = request(Method::POST, "b.txt", &[("Content-Type", FORM_TYPE)], &body);
+        let response = file_post_handler(post, state).await;
+        assert_eq!(response.status, StatusCode::ContentTooLarge);
+        assert!(entries(&root).is_empty());
+        std::fs::remove_dir_all(root)
+    }
+
+    fn site_state(root: &Path, listing: bool) -> State {
+        State::builder()
+            .file_dir(root.to_string_lossy().into_owned())
+            .static_site(Some(StaticSite {
+                listing,
+                ..StaticSite::default()
+            }))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn directory_redirect() -> std::io::Result<()> {
+        let root = temp_root("dir-redirect");
+        std::fs::create_dir(root.join("docs"))?;
+        let response = file_get_handler(get("docs?page=2", &[]), site_state(&root, true)).await;
+        assert_eq!(response.status, StatusCode::MovedPermanently);
+        assert_eq!(header(&response, "Location"), Some("/files/docs/?page=2"));
+
+        // without the static site, directories are not served at all
+        let response = file_get_handler(get("docs/", &[]), state(&root)).await;
+        assert_eq!(response.status, StatusCode::NotFound);
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn index_or_listing() -> std::io::Result<()> {
+        let root = temp_root("dir-index");
+        std::fs::create_dir_all(root.join("site/sub"))?;
+        std::fs::write(root.join("site/index.html"), b"<p>home</p>")?;
+        std::fs::write(root.join("docs.txt"), b"docs")?;
+
+        let response = file_get_handler(get("site/", &[]), site_state(&root, false)).await;
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(read_body(response).await, b"<p>home</p>");
+
+        let response = file_get_handler(get("site/sub/", &[]), site_state(&root, false)).await;
+        assert_eq!(response.status, StatusCode::NotFound);
+
+        let response = file_get_handler(get("", &[]), site_state(&root, true)).await;
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let html = String::from_utf8(read_body(response).await).unwrap();
+        assert!(html.contains("<a href=\"site/\">site/</a>"));
+        assert!(html.contains("<a href=\"docs.txt\">docs.txt</a>"));
+
+        let accept = [("Accept", "application/json")];
+        let response = file_get_handler(get("site/sub/", &accept), site_state(&root, true)).await;
+        assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+        assert_eq!(read_body(response).await, b"[]");
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn ranges() -> std::io::Result<()> {
+        let root = temp_root("ranges");
+        let state = state(&root);
+        std::fs::write(root.join("a.txt"), b"0123456789")?;
+
+        let response = file_get_handler(get("a.txt", &[]), state.clone()).await;
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(header(&response, "Accept-Ranges"), Some("bytes"));
+
+        let response =
+            file_get_handler(get("a.txt", &[("Range", "bytes=-3")]), state.clone()).await;
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 7-9/10"));
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(read_body(response).await, b"789");
+
+        let response = file_get_handler(get("a.txt", &[("Range", "bytes=10-")]), state).await;
+        assert_eq!(response.status, StatusCode::RangeNotSatisfiable);
+        assert_eq!(header(&response, "Accept-Ranges"), Some("bytes"));
+        assert_eq!(header(&response, "Content-Range"), Some("bytes */10"));
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn multipart_ranges() -> std::io::Result<()> {
+        let root = temp_root("multipart-ranges");
+        std::fs::write(root.join("a.txt"), b"0123456789")?;
+
+        // the overlapping ranges go out as one part
+        let range = [("Range", "bytes=7-8,0-1,1-2")];
+        let response = file_get_handler(get("a.txt", &range), state(&root)).await;
+        assert_eq!(response.status, StatusCode::PartialContent);
+        let content_type = header(&response, "Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let len = response.body.as_ref().and_then(Body::len);
+        let body = String::from_utf8(read_body(response).await).unwrap();
+        assert_eq!(len, Some(body.len() as u64));
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Range: bytes 0-2/10\r\n\r\n012\
+                 \r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Range: bytes 7-8/10\r\n\r\n78\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+        std::fs::remove_dir_all(root)
+    }
+
+    #[tokio::test]
+    async fn streamed_file() -> std::io::Result<()> {
+        let root = temp_root("streamed");
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(root.join("big.bin"), &data)?;
+
+        // the length comes from the metadata, the data is only read when sent
+        let handler = middleware::content_length(Box::new(file_get_handler));
+        let response = handler(get("big.bin", &[]), state(&root)).await;
+        assert!(response.body.as_ref().is_some_and(Body::is_stream));
+        assert_eq!(header(&response, "Content-Length"), Some("100000"));
+        assert_eq!(read_body(response).await, data);
+        std::fs::remove_dir_all(root)
+    }
 }
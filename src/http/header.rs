@@ -1,43 +1,187 @@
-use std::{borrow::Borrow, collections::HashMap, hash::Hash};
+use thiserror::Error;
 
-type HeaderMap = HashMap<String, String>;
-#[derive(Debug)]
-pub struct Headers(HeaderMap);
+use crate::http::helpers;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HeaderError {
+    #[error("header line has no colon")]
+    MissingColon,
+
+    #[error("invalid header name")]
+    InvalidName,
+}
+
+/// Header fields in the order they were added.
+///
+/// Names keep the casing they were inserted with, which is what goes out on
+/// the wire, but every lookup ignores case. A name can hold several values,
+/// e.g. repeated `Set-Cookie` fields.
+#[derive(Debug, Default, Clone)]
+pub struct Headers(Vec<(String, String)>);
 
 impl<'h> IntoIterator for &'h Headers {
-    type Item = <&'h HeaderMap as IntoIterator>::Item;
-    type IntoIter = <&'h HeaderMap as IntoIterator>::IntoIter;
+    type Item = &'h (String, String);
+    type IntoIter = std::slice::Iter<'h, (String, String)>;
 
     fn into_iter(self) -> Self::IntoIter {
-        (&self.0).into_iter()
+        self.0.iter()
     }
 }
 
 impl Headers {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self(Vec::new())
     }
 
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Sets `key` to `value`, replacing every value it had before.
     pub fn insert(&mut self, key: String, value: String) {
-        self.0.insert(key, value);
+        match self.position(&key) {
+            Some(i) => {
+                self.0[i].1 = value;
+                let mut seen = 0;
+                self.0.retain(|(name, _)| {
+                    if name.eq_ignore_ascii_case(&key) {
+                        seen += 1;
+                        return seen == 1;
+                    }
+                    true
+                });
+            }
+            None => self.0.push((key, value)),
+        }
+    }
+
+    /// Adds another value for `key`, keeping the ones already present.
+    pub fn append(&mut self, key: String, value: String) {
+        self.0.push((key, value));
+    }
+
+    /// Removes every value of `key`, returning the first one.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let first = self.position(key).map(|i| self.0.remove(i).1);
+        self.0.retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+        first
+    }
+
+    /// Parses a `name: value` line and appends it.
+    pub fn insert_header_line(&mut self, header_line: &str) -> Result<(), HeaderError> {
+        let (key, value) = header_line
+            .split_once(':')
+            .ok_or(HeaderError::MissingColon)?;
+        // no whitespace is allowed between the name and the colon
+        if key.is_empty() || !key.bytes().all(helpers::is_tchar) {
+            return Err(HeaderError::InvalidName);
+        }
+        let value = value.trim_matches([' ', '\t']);
+        self.append(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    /// First value of `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.position(key).map(|i| self.0[i].1.as_str())
+    }
+
+    /// Every value of `key`, in the order they were added.
+    pub fn get_all<'h>(&'h self, key: &'h str) -> impl Iterator<Item = &'h str> {
+        self.0
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
     }
 
-    pub fn insert_header_line(&mut self, header_line: String) {
-        // add error handling
-        let (key, value) = header_line.split_once(':').unwrap();
-        let value = value.trim();
-        self.insert(key.to_owned(), value.to_owned());
+    /// Whether the comma separated list in `key` contains `token`, ignoring
+    /// case, e.g. `close` in `Connection: keep-alive, close`.
+    pub fn has_token(&self, key: &str, token: &str) -> bool {
+        self.get_all(key)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.position(key).is_some()
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|(name, _)| name.eq_ignore_ascii_case(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn case_insensitive() {
+        let mut headers = Headers::new();
+        headers.insert("user-agent".to_string(), "curl".to_string());
+        assert_eq!(headers.get("User-Agent"), Some("curl"));
+        assert!(headers.contains("USER-AGENT"));
+    }
+
+    #[test]
+    fn tokens() {
+        let mut headers = Headers::new();
+        headers.append("Connection".to_string(), "keep-alive, Upgrade".to_string());
+        headers.append("connection".to_string(), "Close".to_string());
+        assert!(headers.has_token("Connection", "close"));
+        assert!(headers.has_token("Connection", "upgrade"));
+        assert!(!headers.has_token("Connection", "keep"));
+    }
+
+    #[test]
+    fn multiple_values() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie".to_string(), "a=1".to_string());
+        headers.append("Content-Type".to_string(), "text/plain".to_string());
+        headers.append("set-cookie".to_string(), "b=2".to_string());
+        assert_eq!(
+            headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+
+        headers.insert("SET-COOKIE".to_string(), "c=3".to_string());
+        let fields: Vec<_> = headers.into_iter().cloned().collect();
+        assert_eq!(
+            fields,
+            [
+                ("Set-Cookie".to_string(), "c=3".to_string()),
+                ("Content-Type".to_string(), "text/plain".to_string()),
+            ]
+        );
+
+        assert_eq!(headers.remove("set-cookie"), Some("c=3".to_string()));
+        assert_eq!(headers.len(), 1);
     }
 
-    pub fn get<Q>(&self, key: &Q) -> Option<&str>
-    where
-        String: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
-    {
-        self.0.get(key).map(|s| s.as_str())
+    #[test]
+    fn header_lines() {
+        let mut headers = Headers::new();
+        assert_eq!(headers.insert_header_line("Host:  localhost:4221 "), Ok(()));
+        assert_eq!(headers.get("host"), Some("localhost:4221"));
+        assert_eq!(
+            headers.insert_header_line("no colon"),
+            Err(HeaderError::MissingColon)
+        );
+        assert_eq!(
+            headers.insert_header_line("Host : localhost"),
+            Err(HeaderError::InvalidName)
+        );
+        assert_eq!(
+            headers.insert_header_line(": localhost"),
+            Err(HeaderError::InvalidName)
+        );
     }
 }
@@ -1,5 +1,6 @@
 use bytes::Buf;
 use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -38,3 +39,260 @@ pub(crate) fn read_n<'a>(cursor: &mut Cursor<&'a [u8]>, n: usize) -> Result<&'a
     cursor.advance(n);
     Ok(&cursor.get_ref()[start..end])
 }
+
+/// Whether `b` may appear in a token such as a method or header name.
+pub(crate) fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// `value` without the control characters a header value may not contain,
+/// so it cannot end the field early. Tabs are kept.
+pub(crate) fn field_value(value: &str) -> String {
+    value
+        .chars()
+        .filter(|&c| c == '\t' || !c.is_ascii_control())
+        .collect()
+}
+
+/// Decodes `%XX` escapes, returning `None` for malformed escapes or if the
+/// decoded bytes are not valid UTF-8.
+pub(crate) fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Escapes everything but unreserved characters, so `segment` can be used
+/// as a single path segment.
+pub(crate) fn percent_encode(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Escapes text for use in HTML content and quoted attribute values.
+pub(crate) fn html_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Quotes `text` as a JSON string.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Splits a header value like `text/plain; charset="utf-8"` into its main
+/// part and its parameters. Parameter names are lowercased, quoted values
+/// unescaped, and malformed parameters skipped.
+pub(crate) fn split_params(value: &str) -> (&str, Vec<(String, String)>) {
+    let (main, mut rest) = value.split_once(';').unwrap_or((value, ""));
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        let (name, after) = match rest.split_once('=') {
+            Some(param) => param,
+            None => break,
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let after = after.trim_start_matches([' ', '\t']);
+        let (param_value, tail) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut param_value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = None;
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => param_value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = Some(i + 1);
+                            break;
+                        }
+                        c => param_value.push(c),
+                    }
+                }
+                match end {
+                    Some(end) => (param_value, &quoted[end..]),
+                    None => break,
+                }
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        if !name.is_empty() {
+            params.push((name, param_value));
+        }
+        rest = tail;
+    }
+    (main.trim(), params)
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86_400;
+    let (hour, min, sec) = (secs % 86_400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {day:02} {} {year} {hour:02}:{min:02}:{sec:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+    )
+}
+
+/// Parses an IMF-fixdate as produced by [`http_date`]. The obsolete RFC 850
+/// and asctime formats are not accepted.
+pub(crate) fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.trim().split(' ');
+    let weekday = parts.next()?.strip_suffix(',')?;
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let time = parts.next()?;
+    if parts.next()? != "GMT" || parts.next().is_some() || !WEEKDAYS.contains(&weekday) {
+        return None;
+    }
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let mut hms = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, min, sec) = (hms.next()??, hms.next()??, hms.next()??);
+    if hms.next().is_some()
+        || year < 1970
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || min > 59
+        || sec > 60
+    {
+        return None;
+    }
+
+    // days since the epoch from a civil date, the inverse of http_date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y % 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146_097 + doe).checked_sub(719_468)?;
+    let secs = days * 86_400 + hour * 3600 + min * 60 + sec;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(http_date(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parse_dates() {
+        for secs in [0, 784_111_777, 951_782_400, 1_792_269_267] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&http_date(time)), Some(time));
+        }
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49"), None);
+    }
+
+    #[test]
+    fn params() {
+        let (main, params) =
+            split_params("form-data; name=\"a;b\"; filename=\"x \\\"y\\\".txt\";size=3");
+        assert_eq!(main, "form-data");
+        assert_eq!(
+            params,
+            [
+                ("name".to_string(), "a;b".to_string()),
+                ("filename".to_string(), "x \"y\".txt".to_string()),
+                ("size".to_string(), "3".to_string()),
+            ]
+        );
+        assert_eq!(split_params("text/plain").1, []);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(percent_encode("a b/ü.txt"), "a%20b%2F%C3%BC.txt");
+        assert_eq!(
+            html_escape("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+        assert_eq!(
+            json_string("say \"hi\"\n\u{1}"),
+            "\"say \\\"hi\\\"\\n\\u0001\""
+        );
+    }
+}
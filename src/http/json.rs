@@ -0,0 +1,472 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::http::helpers;
+
+// deeper documents are not worth the stack they would take to parse
+const MAX_DEPTH: usize = 128;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum JsonError {
+    #[error("unexpected end of JSON input")]
+    Eof,
+
+    #[error("unexpected character in JSON at offset {0}")]
+    Unexpected(usize),
+
+    #[error("JSON nested too deeply")]
+    TooDeep,
+}
+
+/// A JSON document. Objects keep their members in document order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Empty object, to be filled with [`Json::insert`].
+    pub fn object() -> Self {
+        Json::Object(Vec::new())
+    }
+
+    /// Sets the member `key` of an object, replacing an earlier one. Does
+    /// nothing to other values.
+    pub fn insert(mut self, key: &str, value: impl Into<Json>) -> Self {
+        if let Json::Object(members) = &mut self {
+            let value = value.into();
+            match members.iter_mut().find(|(name, _)| name == key) {
+                Some((_, old)) => *old = value,
+                None => members.push((key.to_string(), value)),
+            }
+        }
+        self
+    }
+
+    pub fn parse(input: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos < parser.input.len() {
+            return Err(JsonError::Unexpected(parser.pos));
+        }
+        Ok(value)
+    }
+
+    /// Member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// The number, if it is a whole one that fits an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        let n = self.as_f64()?;
+        let fits = n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64;
+        fits.then_some(n as i64)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Json::Null)
+    }
+}
+
+/// Writes compact JSON. Numbers that JSON cannot express become `null`.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Number(n) if n.is_finite() => write!(f, "{n}"),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write!(f, "{}", helpers::json_string(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{value}", helpers::json_string(name))?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+macro_rules! from_number {
+    ($($ty:ty),*) => {
+        $(impl From<$ty> for Json {
+            fn from(n: $ty) -> Self {
+                Json::Number(n as f64)
+            }
+        })*
+    };
+}
+
+from_number!(i32, i64, u32, u64, usize, f64);
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+        self.skip_whitespace();
+        match self.peek()? {
+            b'{' => self.object(depth),
+            b'[' => self.array(depth),
+            b'"' => self.string().map(Json::String),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => Err(JsonError::Unexpected(self.pos)),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.eat(b'}') {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek()? != b'"' {
+                return Err(JsonError::Unexpected(self.pos));
+            }
+            let name = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            members.push((name, self.value(depth + 1)?));
+            self.skip_whitespace();
+            if self.eat(b'}') {
+                return Ok(Json::Object(members));
+            }
+            self.expect(b',')?;
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.eat(b']') {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            if self.eat(b']') {
+                return Ok(Json::Array(items));
+            }
+            self.expect(b',')?;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            // copy runs of plain characters at once, they are valid UTF-8
+            let start = self.pos;
+            while let Some(&b) = self.input.get(self.pos) {
+                if b == b'"' || b == b'\\' || b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            out.push_str(std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default());
+
+            match self.peek()? {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    let escaped = match self.peek()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            self.pos += 1;
+                            out.push(self.unicode_escape()?);
+                            continue;
+                        }
+                        _ => return Err(JsonError::Unexpected(self.pos)),
+                    };
+                    self.pos += 1;
+                    out.push(escaped);
+                }
+                _ => return Err(JsonError::Unexpected(self.pos)),
+            }
+        }
+    }
+
+    /// Decodes the digits after `\u`, joining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or(JsonError::Unexpected(self.pos - 4));
+        }
+        if !self.input[self.pos..].starts_with(b"\\u") {
+            return Err(JsonError::Unexpected(self.pos));
+        }
+        self.pos += 2;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(JsonError::Unexpected(self.pos - 4));
+        }
+        let code = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+        char::from_u32(code).ok_or(JsonError::Unexpected(self.pos - 4))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .ok_or(JsonError::Eof)?;
+        let code = std::str::from_utf8(digits)
+            .ok()
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(JsonError::Unexpected(self.pos))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        self.eat(b'-');
+        // no leading zeros
+        if !self.eat(b'0') && self.digits() == 0 {
+            return Err(self.unexpected());
+        }
+        if self.eat(b'.') && self.digits() == 0 {
+            return Err(self.unexpected());
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if self.digits() == 0 {
+                return Err(self.unexpected());
+            }
+        }
+        let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| JsonError::Unexpected(start))
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if self.input[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else if word.as_bytes().starts_with(&self.input[self.pos..]) {
+            Err(JsonError::Eof)
+        } else {
+            Err(JsonError::Unexpected(self.pos))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.input.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Result<u8, JsonError> {
+        self.input.get(self.pos).copied().ok_or(JsonError::Eof)
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        let matched = self.input.get(self.pos) == Some(&b);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), JsonError> {
+        if self.eat(b) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn unexpected(&self) -> JsonError {
+        if self.pos < self.input.len() {
+            JsonError::Unexpected(self.pos)
+        } else {
+            JsonError::Eof
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<(), JsonError> {
+        let json = Json::parse(
+            r#" {"name": "caf\u00e9 \ud83d\ude00", "size": -1.5e2, "tags": [true, null, 0],
+                "nested": {"empty": {}, "list": []}} "#,
+        )?;
+        assert_eq!(json.get("name").and_then(Json::as_str), Some("café 😀"));
+        assert_eq!(json.get("size").and_then(Json::as_f64), Some(-150.0));
+        assert_eq!(json.get("size").and_then(Json::as_i64), Some(-150));
+        let tags = json
+            .get("tags")
+            .and_then(Json::as_array)
+            .unwrap_or_default();
+        assert_eq!(tags, [Json::Bool(true), Json::Null, Json::Number(0.0)]);
+        assert_eq!(
+            json.get("nested").and_then(|nested| nested.get("empty")),
+            Some(&Json::object())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(Json::parse(""), Err(JsonError::Eof));
+        assert_eq!(Json::parse("[1, 2"), Err(JsonError::Eof));
+        assert_eq!(Json::parse("tru"), Err(JsonError::Eof));
+        assert_eq!(Json::parse("{\"a\" 1}"), Err(JsonError::Unexpected(5)));
+        assert_eq!(Json::parse("[1,]"), Err(JsonError::Unexpected(3)));
+        assert_eq!(Json::parse("01"), Err(JsonError::Unexpected(1)));
+        assert_eq!(Json::parse("1."), Err(JsonError::Eof));
+        assert_eq!(Json::parse("\"\\ud800\""), Err(JsonError::Unexpected(7)));
+        assert_eq!(Json::parse("\"a\nb\""), Err(JsonError::Unexpected(2)));
+        assert_eq!(Json::parse("{} {}"), Err(JsonError::Unexpected(3)));
+        let deep = "[".repeat(MAX_DEPTH + 2);
+        assert_eq!(Json::parse(&deep), Err(JsonError::TooDeep));
+    }
+
+    #[test]
+    fn display() {
+        let json = Json::object()
+            .insert("name", "a \"b\"")
+            .insert("size", 3)
+            .insert("ratio", 0.5)
+            .insert("missing", None::<&str>)
+            .insert("items", vec![Json::from(true), Json::Number(f64::NAN)])
+            .insert("size", 4);
+        let text = json.to_string();
+        assert_eq!(
+            text,
+            r#"{"name":"a \"b\"","size":4,"ratio":0.5,"missing":null,"items":[true,null]}"#
+        );
+        assert_eq!(
+            Json::parse(&text).map(|json| json.get("size").cloned()),
+            Ok(Some(Json::Number(4.0)))
+        );
+    }
+}
@@ -1,17 +1,18 @@
-use crate::http::{request::Request, State};
+use crate::http::{request::Request, Body, Method, State};
 
-use super::{header::Headers, response::Response, router::Handler, status::StatusCode};
+use super::{
+    conditional::Validators, header::Headers, response::Response, router::Handler,
+    status::StatusCode,
+};
 
 pub fn content_length(handler: Handler) -> Handler {
     Box::new(move |request: Request, state: State| {
         let resp = handler(request, state);
         Box::pin(async move {
             let mut resp = resp.await;
-            let len = match &resp.body {
-                Some(body) => body.data.len(),
-                None => 0,
-            };
-            if len > 0 {
+            // streams of unknown length go out chunked instead
+            let len = resp.body.as_ref().and_then(Body::len);
+            if let Some(len) = len.filter(|_| resp.status.allows_body()) {
                 let mut headers = resp.headers.take().unwrap_or(Headers::new());
                 headers.insert("Content-Length".to_string(), len.to_string());
                 resp.headers = Some(headers);
@@ -23,19 +24,23 @@ pub fn content_length(handler: Handler) -> Handler {
 
 pub fn content_encoding(handler: Handler) -> Handler {
     Box::new(move |request: Request, state: State| {
-        let content_encoding = match request.metadata.headers.get("Accept-Encoding") {
-            Some(encodings) => encodings
-                .split(",")
-                .map(|s| s.trim())
-                .find(|encoding| state.supported_encoding(encoding))
-                .map(str::to_string),
-            None => None,
-        };
+        let content_encoding = request
+            .metadata
+            .headers
+            .get_all("Accept-Encoding")
+            .flat_map(|encodings| encodings.split(','))
+            .map(|s| s.trim())
+            .find(|encoding| state.supported_encoding(encoding))
+            .map(str::to_string);
 
         let resp = handler(request, state.clone());
         if let Some(content_encoding) = content_encoding {
             return Box::pin(async move {
                 let mut resp = resp.await;
+                // ranges refer to the unencoded bytes
+                if resp.status == StatusCode::PartialContent {
+                    return resp;
+                }
                 let body = match resp.body.take() {
                     Some(body) => body,
                     None => return resp,
@@ -45,9 +50,18 @@ pub fn content_encoding(handler: Handler) -> Handler {
                     Ok(encoded_body) => encoded_body,
                     Err(_) => return Response::from_status(StatusCode::Internal),
                 };
+                let length_known = encoded_body.len().is_some();
                 resp.body = Some(encoded_body);
 
                 let mut headers = resp.headers.take().unwrap_or(Headers::new());
+                if !length_known {
+                    // any length set by the handler was for the unencoded stream
+                    headers.remove("Content-Length");
+                }
+                // the encoded bytes differ, so a strong tag no longer holds
+                if let Some(etag) = headers.get("ETag").filter(|etag| etag.starts_with('"')) {
+                    headers.insert("ETag".to_string(), format!("W/{etag}"));
+                }
                 headers.insert("Content-Encoding".to_string(), content_encoding);
                 resp.headers = Some(headers);
                 resp
@@ -56,3 +70,185 @@ pub fn content_encoding(handler: Handler) -> Handler {
         resp
     })
 }
+
+/// Tags successful in-memory responses without an `ETag` with a weak one
+/// from their content, and answers `If-None-Match` for them with
+/// `304 Not Modified`. Streamed bodies are left alone.
+pub fn etag(handler: Handler) -> Handler {
+    Box::new(move |request: Request, state: State| {
+        let conditional = matches!(request.metadata.method, Method::GET | Method::HEAD);
+        let request_headers = request.metadata.headers.clone();
+        let resp = handler(request, state);
+        Box::pin(async move {
+            let mut resp = resp.await;
+            if resp.status != StatusCode::Ok {
+                return resp;
+            }
+            let validators = match &resp.body {
+                Some(Body::Full(data)) => Validators::for_data(data),
+                _ => return resp,
+            };
+            let mut headers = resp.headers.take().unwrap_or_default();
+            if headers.contains("ETag") {
+                resp.headers = Some(headers);
+                return resp;
+            }
+            if conditional && validators.not_modified(&request_headers) {
+                return validators.not_modified_response();
+            }
+            validators.apply(&mut headers);
+            resp.headers = Some(headers);
+            resp
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::http::request::Metadata;
+
+    fn respond_with(response: fn() -> Response) -> Handler {
+        Box::new(move |_, _| Box::pin(async move { response() }))
+    }
+
+    async fn call(handler: &Handler, method: Method, headers: Headers) -> Response {
+        call_with(handler, method, headers, State::builder().build()).await
+    }
+
+    async fn call_with(
+        handler: &Handler,
+        method: Method,
+        headers: Headers,
+        state: State,
+    ) -> Response {
+        let metadata = Metadata::new(method, "/".to_string(), headers);
+        handler(Request::new(metadata, None), state).await
+    }
+
+    fn headers(fields: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in fields {
+            headers.insert(name.to_string(), value.to_string());
+        }
+        headers
+    }
+
+    fn header<'r>(response: &'r Response, name: &str) -> Option<&'r str> {
+        response.headers.as_ref()?.get(name)
+    }
+
+    #[tokio::test]
+    async fn content_length_of_bodies() {
+        let handler = content_length(respond_with(|| {
+            Response::from_data(StatusCode::Ok, Headers::new(), b"abc".to_vec())
+        }));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(header(&response, "Content-Length"), Some("3"));
+
+        // an empty body still needs a length for the client to see its end
+        let handler = content_length(respond_with(|| {
+            Response::from_data(StatusCode::Ok, Headers::new(), Vec::new())
+        }));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(header(&response, "Content-Length"), Some("0"));
+
+        let handler = content_length(respond_with(|| {
+            Response::from_body(
+                StatusCode::Ok,
+                Headers::new(),
+                Body::from_reader(tokio::io::empty(), None),
+            )
+        }));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(header(&response, "Content-Length"), None);
+
+        let handler = content_length(respond_with(|| {
+            Response::from_data(StatusCode::NotModified, Headers::new(), b"abc".to_vec())
+        }));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(header(&response, "Content-Length"), None);
+    }
+
+    fn hello() -> Response {
+        Response::from_data(StatusCode::Ok, Headers::new(), b"hello".to_vec())
+    }
+
+    #[tokio::test]
+    async fn etag_of_dynamic_bodies() {
+        let handler = etag(respond_with(hello));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(response.status, StatusCode::Ok);
+        let tag = header(&response, "ETag").unwrap().to_string();
+        assert!(tag.starts_with("W/\""), "{tag} is not weak");
+
+        let response = call(&handler, Method::GET, headers(&[("If-None-Match", &tag)])).await;
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert!(response.body.is_none());
+        assert_eq!(header(&response, "ETag"), Some(tag.as_str()));
+        // only GET and HEAD can be answered from the client's copy
+        let response = call(&handler, Method::POST, headers(&[("If-None-Match", &tag)])).await;
+        assert_eq!(response.status, StatusCode::Ok);
+        let response = call(
+            &handler,
+            Method::GET,
+            headers(&[("If-None-Match", "W/\"x\"")]),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::Ok);
+
+        // streams and tags set by the handler are left alone
+        let handler = etag(respond_with(|| {
+            Response::from_body(
+                StatusCode::Ok,
+                Headers::new(),
+                Body::from_reader(tokio::io::empty(), None),
+            )
+        }));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(header(&response, "ETag"), None);
+        let handler = etag(respond_with(|| {
+            Response::from_data(
+                StatusCode::Ok,
+                headers(&[("ETag", "\"v1\"")]),
+                b"a".to_vec(),
+            )
+        }));
+        let response = call(&handler, Method::GET, Headers::new()).await;
+        assert_eq!(header(&response, "ETag"), Some("\"v1\""));
+    }
+
+    #[tokio::test]
+    async fn encoding_weakens_etag() {
+        let state = State::builder()
+            .encoding("gzip".to_string(), crate::http::encoders::gzip_encoder)
+            .build();
+        let handler = content_encoding(respond_with(|| {
+            Response::from_data(
+                StatusCode::Ok,
+                headers(&[("ETag", "\"v1\"")]),
+                b"a".to_vec(),
+            )
+        }));
+        let gzip = headers(&[("Accept-Encoding", "gzip")]);
+        let response = call_with(&handler, Method::GET, gzip.clone(), state.clone()).await;
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&response, "ETag"), Some("W/\"v1\""));
+
+        // weak tags stay as they are, and so do unencoded responses
+        let handler = content_encoding(respond_with(|| {
+            Response::from_data(
+                StatusCode::Ok,
+                headers(&[("ETag", "W/\"v1\"")]),
+                b"a".to_vec(),
+            )
+        }));
+        let response = call_with(&handler, Method::GET, gzip, state.clone()).await;
+        assert_eq!(header(&response, "ETag"), Some("W/\"v1\""));
+        let response = call_with(&handler, Method::GET, Headers::new(), state).await;
+        assert_eq!(header(&response, "Content-Encoding"), None);
+        assert_eq!(header(&response, "ETag"), Some("W/\"v1\""));
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How much of a file is looked at to guess its type.
+pub const SNIFF_LEN: usize = 512;
+
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Maps file names to media types by their extension.
+///
+/// The built-in table can be extended or overridden per extension. Files
+/// without a known extension are `application/octet-stream`, unless
+/// sniffing is on and their first bytes give them away.
+#[derive(Clone, Debug, Default)]
+pub struct MimeTypes {
+    overrides: HashMap<String, String>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves files ending in `.extension` as `mime`, which is sent as is.
+    pub fn insert(mut self, extension: &str, mime: &str) -> Self {
+        self.overrides
+            .insert(extension.to_ascii_lowercase(), mime.to_string());
+        self
+    }
+
+    /// Guess the type of files without a known extension from their content.
+    pub fn sniff(mut self, sniff: bool) -> Self {
+        self.sniff = sniff;
+        self
+    }
+
+    pub fn sniffs(&self) -> bool {
+        self.sniff
+    }
+
+    /// Type for `path` going by its extension alone.
+    pub fn lookup(&self, path: &Path) -> Option<String> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        if let Some(mime) = self.overrides.get(&extension) {
+            return Some(mime.clone());
+        }
+        from_extension(&extension).map(with_charset)
+    }
+
+    /// Type for `path`, where `head` is the start of its content if it may
+    /// be sniffed.
+    pub fn content_type(&self, path: &Path, head: Option<&[u8]>) -> String {
+        if let Some(mime) = self.lookup(path) {
+            return mime;
+        }
+        match head {
+            Some(head) if self.sniff => with_charset(sniff(head)),
+            _ => DEFAULT_TYPE.to_string(),
+        }
+    }
+}
+
+fn from_extension(extension: &str) -> Option<&'static str> {
+    let mime = match extension {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "txt" | "text" | "log" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "json" | "map" => "application/json",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "avif" => "image/avif",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+/// Text is always served as UTF-8, so say so.
+fn with_charset(mime: &str) -> String {
+    let textual = mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/json" | "application/xml" | "image/svg+xml"
+        );
+    if textual {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+/// Guesses a type from the first bytes of a file, falling back to
+/// `text/plain` for anything that reads as text.
+pub fn sniff(head: &[u8]) -> &'static str {
+    const SIGNATURES: [(&[u8], &str); 9] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b\x08", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return mime;
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // the cut may have split a character
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return DEFAULT_TYPE,
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+    {
+        return DEFAULT_TYPE;
+    }
+    let start = text.trim_start().get(..14).unwrap_or(text.trim_start());
+    let start = start.to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html"
+    } else if start.starts_with("<?xml") {
+        "application/xml"
+    } else if start.starts_with("<svg") {
+        "image/svg+xml"
+    } else {
+        "text/plain"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn extensions() {
+        let mime = MimeTypes::new().insert("LOG", "text/x-log");
+        let lookup = |path: &str| mime.lookup(Path::new(path));
+        assert_eq!(
+            lookup("index.html").as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(lookup("a/b/Logo.PNG").as_deref(), Some("image/png"));
+        assert_eq!(lookup("app.log").as_deref(), Some("text/x-log"));
+        assert_eq!(lookup("archive.unknown"), None);
+        assert_eq!(lookup("README"), None);
+    }
+
+    #[test]
+    fn sniffing() {
+        let path = Path::new("noext");
+        assert_eq!(
+            MimeTypes::new().content_type(path, Some(b"hello")),
+            "application/octet-stream"
+        );
+
+        let mime = MimeTypes::new().sniff(true);
+        assert_eq!(
+            mime.content_type(path, Some(b"  <!DOCTYPE html><p>hi")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            mime.content_type(path, Some(b"\x89PNG\r\n\x1a\n\0\0")),
+            "image/png"
+        );
+        assert_eq!(
+            mime.content_type(path, Some("caf\u{e9}".as_bytes()[..4].as_ref())),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            mime.content_type(path, Some(b"\0\x01\x02")),
+            "application/octet-stream"
+        );
+        // a known extension wins over the content
+        assert_eq!(
+            mime.content_type(Path::new("x.css"), Some(b"<html>")),
+            "text/css; charset=utf-8"
+        );
+    }
+}
@@ -0,0 +1,527 @@
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::str;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use thiserror::Error;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::http::header::Headers;
+use crate::http::helpers;
+use crate::http::request::Request;
+use crate::http::status::StatusCode;
+use crate::http::{Body, BodyReader};
+
+const READ_SIZE: usize = 8 * 1024;
+// RFC 2046 caps boundaries at 70 characters
+const MAX_BOUNDARY_LEN: usize = 70;
+
+#[derive(Error, Debug)]
+pub enum MultipartError {
+    #[error("body is not multipart/form-data")]
+    NotMultipart,
+
+    #[error("multipart body has no valid boundary")]
+    MissingBoundary,
+
+    #[error("malformed multipart body")]
+    Malformed,
+
+    #[error("multipart part larger than allowed")]
+    PartTooLarge,
+
+    #[error("too many multipart parts")]
+    TooManyParts,
+
+    #[error("io error while reading multipart body")]
+    Io(#[from] std::io::Error),
+}
+
+impl MultipartError {
+    /// Status to answer a request whose body could not be used with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MultipartError::NotMultipart => StatusCode::UnsupportedMediaType,
+            MultipartError::MissingBoundary | MultipartError::Malformed => StatusCode::BadRequest,
+            MultipartError::PartTooLarge | MultipartError::TooManyParts => {
+                StatusCode::ContentTooLarge
+            }
+            MultipartError::Io(_) => StatusCode::Internal,
+        }
+    }
+
+    /// Recovers the multipart error that a [`PartReader`] wrapped in `err`.
+    pub fn from_io(err: &std::io::Error) -> Option<&MultipartError> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+/// Bounds on what a single multipart body may contain.
+#[derive(Clone, Debug)]
+pub struct PartLimits {
+    /// Largest data of one part, whether it is streamed or collected.
+    pub max_part_size: u64,
+    /// Most bytes of header fields of one part.
+    pub max_header_bytes: usize,
+    pub max_parts: usize,
+}
+
+impl Default for PartLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: 16 * 1024 * 1024,
+            max_header_bytes: 8 * 1024,
+            max_parts: 100,
+        }
+    }
+}
+
+/// Header section of one part.
+#[derive(Debug)]
+pub struct Part {
+    /// Form field name from `Content-Disposition`.
+    pub name: Option<String>,
+    /// Name of the uploaded file, as sent by the client. Never use it as a
+    /// path without sanitizing it.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: Headers,
+}
+
+impl Part {
+    fn from_headers(headers: Headers) -> Self {
+        let mut name = None;
+        let mut filename = None;
+        if let Some(disposition) = headers.get("Content-Disposition") {
+            for (key, value) in helpers::split_params(disposition).1 {
+                match key.as_str() {
+                    "name" => name = Some(value),
+                    "filename" => filename = Some(value),
+                    _ => {}
+                }
+            }
+        }
+        Self {
+            name,
+            filename,
+            content_type: headers.get("Content-Type").map(str::to_string),
+            headers,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Inside the data of a part, or the preamble before the first one.
+    Data,
+    /// Right after a delimiter, before its line ends.
+    Boundary,
+    Headers,
+    Done,
+}
+
+/// Streaming parser for `multipart/form-data` bodies.
+///
+/// Parts are visited in order with [`Multipart::next_part`]; the data of
+/// the current part can then be streamed with [`Multipart::reader`] or
+/// collected with [`Multipart::bytes`]. Data that is not read is skipped
+/// when moving on, so the body is never held in memory as a whole.
+pub struct Multipart {
+    reader: BodyReader,
+    /// `CRLF--boundary`, which ends the data of every part.
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+    state: State,
+    eof: bool,
+    part_len: u64,
+    parts: usize,
+    limits: PartLimits,
+}
+
+impl Multipart {
+    pub fn new(reader: BodyReader, boundary: &str, limits: PartLimits) -> Self {
+        let mut buf = BytesMut::with_capacity(READ_SIZE);
+        // lets the first delimiter match even without a preamble
+        buf.extend_from_slice(b"\r\n");
+        Self {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            buf,
+            state: State::Data,
+            eof: false,
+            part_len: 0,
+            parts: 0,
+            limits,
+        }
+    }
+
+    /// Takes the body of a `multipart/form-data` request.
+    pub fn from_request(request: &mut Request, limits: PartLimits) -> Result<Self, MultipartError> {
+        let content_type = request
+            .metadata
+            .headers
+            .get("Content-Type")
+            .ok_or(MultipartError::NotMultipart)?;
+        let (mime, params) = helpers::split_params(content_type);
+        if !mime.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(MultipartError::NotMultipart);
+        }
+        let boundary = params
+            .into_iter()
+            .find(|(name, _)| name == "boundary")
+            .map(|(_, boundary)| boundary)
+            .filter(|boundary| (1..=MAX_BOUNDARY_LEN).contains(&boundary.len()))
+            .ok_or(MultipartError::MissingBoundary)?;
+
+        let reader = match request.body.take() {
+            Some(body) => body.into_reader(),
+            None => Body::Full(Vec::new()).into_reader(),
+        };
+        Ok(Self::new(reader, &boundary, limits))
+    }
+
+    /// Moves on to the next part, skipping whatever is left of the current
+    /// one. `None` after the last part.
+    pub async fn next_part(&mut self) -> Result<Option<Part>, MultipartError> {
+        poll_fn(|cx| self.poll_next_part(cx)).await
+    }
+
+    /// Reader over the data of the current part. Errors other than IO ones
+    /// come out as `InvalidData`, see [`MultipartError::from_io`].
+    pub fn reader(&mut self) -> PartReader<'_> {
+        PartReader { multipart: self }
+    }
+
+    /// Collects the data of the current part.
+    pub async fn bytes(&mut self) -> Result<Vec<u8>, MultipartError> {
+        let mut data = Vec::new();
+        let mut chunk = [0; READ_SIZE];
+        loop {
+            let mut out = ReadBuf::new(&mut chunk);
+            poll_fn(|cx| self.poll_data(cx, &mut out)).await?;
+            if out.filled().is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(out.filled());
+        }
+    }
+
+    /// Collects the data of the current part as UTF-8 text.
+    pub async fn text(&mut self) -> Result<String, MultipartError> {
+        String::from_utf8(self.bytes().await?).map_err(|_| MultipartError::Malformed)
+    }
+
+    fn poll_next_part(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Part>, MultipartError>> {
+        loop {
+            match self.state {
+                State::Data => match find(&self.buf, &self.delimiter) {
+                    Some(i) => {
+                        self.buf.advance(i + self.delimiter.len());
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        self.buf.advance(self.buf.len().saturating_sub(keep));
+                        ready!(self.poll_fill(cx))?;
+                    }
+                },
+                State::Boundary => {
+                    if self.buf.starts_with(b"--") {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    match find(&self.buf, b"\r\n") {
+                        Some(i) => {
+                            // only transport padding may follow the boundary
+                            if !self.buf[..i].iter().all(|b| *b == b' ' || *b == b'\t') {
+                                return Poll::Ready(Err(MultipartError::Malformed));
+                            }
+                            self.buf.advance(i + 2);
+                            self.state = State::Headers;
+                        }
+                        None if self.buf.len() > 2 * MAX_BOUNDARY_LEN => {
+                            return Poll::Ready(Err(MultipartError::Malformed));
+                        }
+                        None => ready!(self.poll_fill(cx))?,
+                    }
+                }
+                State::Headers => {
+                    let end = if self.buf.starts_with(b"\r\n") {
+                        Some(0)
+                    } else {
+                        find(&self.buf, b"\r\n\r\n").map(|i| i + 2)
+                    };
+                    let end = match end {
+                        Some(end) => end,
+                        None if self.buf.len() > self.limits.max_header_bytes => {
+                            return Poll::Ready(Err(MultipartError::PartTooLarge));
+                        }
+                        None => {
+                            ready!(self.poll_fill(cx))?;
+                            continue;
+                        }
+                    };
+                    if end > self.limits.max_header_bytes {
+                        return Poll::Ready(Err(MultipartError::PartTooLarge));
+                    }
+
+                    let mut headers = Headers::new();
+                    let section =
+                        str::from_utf8(&self.buf[..end]).map_err(|_| MultipartError::Malformed)?;
+                    for line in section.split_terminator("\r\n") {
+                        headers
+                            .insert_header_line(line)
+                            .map_err(|_| MultipartError::Malformed)?;
+                    }
+                    self.buf.advance(end + 2);
+
+                    self.parts += 1;
+                    if self.parts > self.limits.max_parts {
+                        return Poll::Ready(Err(MultipartError::TooManyParts));
+                    }
+                    self.part_len = 0;
+                    self.state = State::Data;
+                    return Poll::Ready(Ok(Some(Part::from_headers(headers))));
+                }
+                State::Done => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+
+    /// Puts the next data of the current part into `out`, leaving it empty
+    /// at the end of the part.
+    fn poll_data(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), MultipartError>> {
+        // before the first part there is nothing to read
+        if self.parts == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        while self.state == State::Data {
+            let available = match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.buf.advance(self.delimiter.len());
+                    self.state = State::Boundary;
+                    break;
+                }
+                Some(i) => i,
+                // the end could hold the start of a delimiter
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available == 0 {
+                ready!(self.poll_fill(cx))?;
+                continue;
+            }
+
+            let n = available.min(out.remaining());
+            self.part_len += n as u64;
+            if self.part_len > self.limits.max_part_size {
+                return Poll::Ready(Err(MultipartError::PartTooLarge));
+            }
+            out.put_slice(&self.buf[..n]);
+            self.buf.advance(n);
+            break;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reads more of the body, which must not end before the closing
+    /// delimiter.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), MultipartError>> {
+        if self.eof {
+            return Poll::Ready(Err(MultipartError::Malformed));
+        }
+        let mut chunk = [0; READ_SIZE];
+        let mut read_buf = ReadBuf::new(&mut chunk);
+        ready!(self.reader.as_mut().poll_read(cx, &mut read_buf))?;
+        if read_buf.filled().is_empty() {
+            self.eof = true;
+        }
+        self.buf.extend_from_slice(read_buf.filled());
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Data of the current part of a [`Multipart`] as an [`AsyncRead`].
+pub struct PartReader<'m> {
+    multipart: &'m mut Multipart,
+}
+
+impl AsyncRead for PartReader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match ready!(this.multipart.poll_data(cx, buf)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(MultipartError::Io(e)) => Poll::Ready(Err(e)),
+            Err(e) => Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::http::request::Metadata;
+    use crate::http::Method;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a b.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\n--Xy not a boundary\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    /// Hands out `data` a few bytes at a time, to split delimiters across
+    /// reads.
+    struct Trickle(&'static [u8]);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let n = self.0.len().min(3).min(buf.remaining());
+            buf.put_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn multipart(body: &'static [u8], limits: PartLimits) -> Multipart {
+        Multipart::new(Box::pin(Trickle(body)), "XyZ", limits)
+    }
+
+    async fn parts(multipart: &mut Multipart) -> Result<Vec<(Part, Vec<u8>)>, MultipartError> {
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next_part().await? {
+            let data = multipart.bytes().await?;
+            parts.push((part, data));
+        }
+        Ok(parts)
+    }
+
+    #[tokio::test]
+    async fn fields_and_files() -> Result<(), MultipartError> {
+        let mut multipart = multipart(BODY, PartLimits::default());
+        let parts = parts(&mut multipart).await?;
+        assert_eq!(parts.len(), 2);
+
+        let (field, data) = &parts[0];
+        assert_eq!(field.name.as_deref(), Some("title"));
+        assert!(!field.is_file());
+        assert_eq!(data, b"hello");
+
+        let (file, data) = &parts[1];
+        assert_eq!(file.name.as_deref(), Some("file"));
+        assert_eq!(file.filename.as_deref(), Some("a b.txt"));
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(data, b"line one\r\n--Xy not a boundary");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_part() -> Result<(), MultipartError> {
+        let mut multipart = multipart(BODY, PartLimits::default());
+        multipart.next_part().await?;
+        // the first part is skipped without being read
+        multipart.next_part().await?;
+        let mut data = String::new();
+        multipart.reader().read_to_string(&mut data).await?;
+        assert_eq!(data, "line one\r\n--Xy not a boundary");
+        assert!(multipart.next_part().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let limits = PartLimits {
+            max_part_size: 8,
+            ..PartLimits::default()
+        };
+        let result = parts(&mut multipart(BODY, limits)).await;
+        assert!(matches!(result, Err(MultipartError::PartTooLarge)));
+
+        let limits = PartLimits {
+            max_parts: 1,
+            ..PartLimits::default()
+        };
+        let result = parts(&mut multipart(BODY, limits)).await;
+        assert!(matches!(result, Err(MultipartError::TooManyParts)));
+
+        let mut multipart = multipart(BODY, PartLimits::default());
+        multipart.next_part().await.unwrap();
+        multipart.next_part().await.unwrap();
+        multipart.limits.max_part_size = 4;
+        let err = multipart.reader().read_to_end(&mut Vec::new()).await;
+        let err = err.unwrap_err();
+        assert!(matches!(
+            MultipartError::from_io(&err),
+            Some(MultipartError::PartTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        let truncated = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nno end";
+        let result = parts(&mut multipart(truncated, PartLimits::default())).await;
+        assert!(matches!(result, Err(MultipartError::Malformed)));
+
+        let result = parts(&mut multipart(b"--XyZjunk\r\n", PartLimits::default())).await;
+        assert!(matches!(result, Err(MultipartError::Malformed)));
+    }
+
+    #[test]
+    fn content_types() {
+        let request = |content_type: &str| {
+            let mut headers = Headers::new();
+            headers.insert("Content-Type".to_string(), content_type.to_string());
+            let metadata = Metadata::new(Method::POST, "/".to_string(), headers);
+            Request::new(metadata, None)
+        };
+        let status = |content_type: &str| {
+            Multipart::from_request(&mut request(content_type), PartLimits::default())
+                .err()
+                .map(|e| e.status())
+        };
+        assert_eq!(status("multipart/form-data; boundary=\"a b\""), None);
+        assert_eq!(
+            status("application/json"),
+            Some(StatusCode::UnsupportedMediaType)
+        );
+        assert_eq!(status("multipart/form-data"), Some(StatusCode::BadRequest));
+        assert_eq!(
+            status(&format!("multipart/form-data; boundary={}", "x".repeat(71))),
+            Some(StatusCode::BadRequest)
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// more ranges than this in one request is not a real client
+const MAX_RANGES: usize = 64;
+
+/// Inclusive byte range within a representation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes in the range, never zero.
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Value for the `Content-Range` header of this range.
+    pub fn content_range(&self, complete_len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, complete_len)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeOutcome {
+    /// The header is absent, malformed or not about bytes: serve everything.
+    Full,
+    /// The satisfiable ranges in ascending order, with overlapping and
+    /// adjacent ones merged.
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlap the representation.
+    Unsatisfiable,
+}
+
+/// Evaluates a `Range` header against a representation of `len` bytes.
+///
+/// Accepts `first-last`, open ended `first-` and suffix `-count` specs in a
+/// comma separated list. Anything that does not parse is ignored as a whole,
+/// as the header is only a hint.
+pub fn evaluate(header: Option<&str>, len: u64) -> RangeOutcome {
+    let specs = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(specs) => specs,
+        None => return RangeOutcome::Full,
+    };
+
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    // without a single range spec the header is malformed, not unsatisfiable
+    if specs.is_empty() {
+        return RangeOutcome::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeOutcome::Full,
+        };
+        let range = match (parse_pos(first), parse_pos(last)) {
+            (Some(Some(first)), Some(last)) => {
+                if last.is_some_and(|last| last < first) {
+                    return RangeOutcome::Full;
+                }
+                if first >= len {
+                    continue;
+                }
+                let end = last.map_or(len - 1, |last| last.min(len - 1));
+                ByteRange { start: first, end }
+            }
+            (Some(None), Some(Some(suffix))) => {
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                }
+            }
+            _ => return RangeOutcome::Full,
+        };
+        ranges.push(range);
+        if ranges.len() > MAX_RANGES {
+            return RangeOutcome::Full;
+        }
+    }
+
+    if ranges.is_empty() {
+        RangeOutcome::Unsatisfiable
+    } else {
+        RangeOutcome::Partial(coalesce(ranges))
+    }
+}
+
+/// Sorts `ranges` and merges those that overlap or touch, so no byte is
+/// sent twice.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// `Some(None)` for an empty position, `None` for garbage.
+fn parse_pos(pos: &str) -> Option<Option<u64>> {
+    if pos.is_empty() {
+        return Some(None);
+    }
+    if !pos.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    pos.parse().ok().map(Some)
+}
+
+/// Framing of a `multipart/byteranges` body. The parts only need their data
+/// put between `part_header` and the closing delimiter.
+pub struct Multipart {
+    pub boundary: String,
+    content_type: String,
+    complete_len: u64,
+}
+
+impl Multipart {
+    pub fn new(content_type: &str, complete_len: u64) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos() as u64);
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self {
+            boundary: format!("byteranges-{nanos:08x}{count:08x}"),
+            content_type: content_type.to_string(),
+            complete_len,
+        }
+    }
+
+    /// Value of the `Content-Type` header of the whole response.
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    pub fn part_header(&self, range: &ByteRange) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary,
+            self.content_type,
+            range.content_range(self.complete_len)
+        )
+    }
+
+    pub fn closing(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// Length of the complete body for `ranges`, known before any data is read.
+    pub fn body_len(&self, ranges: &[ByteRange]) -> u64 {
+        let parts: u64 = ranges
+            .iter()
+            .map(|range| self.part_header(range).len() as u64 + range.size())
+            .sum();
+        parts + self.closing().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeOutcome {
+        RangeOutcome::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(evaluate(Some("bytes=0-499"), 1000), partial(&[(0, 499)]));
+        assert_eq!(evaluate(Some("bytes=500-"), 1000), partial(&[(500, 999)]));
+        assert_eq!(evaluate(Some("bytes=-200"), 1000), partial(&[(800, 999)]));
+        assert_eq!(evaluate(Some("bytes=-2000"), 1000), partial(&[(0, 999)]));
+        assert_eq!(
+            evaluate(Some("bytes=900-5000"), 1000),
+            partial(&[(900, 999)])
+        );
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            evaluate(Some("bytes=0-0, 10-19,-1"), 100),
+            partial(&[(0, 0), (10, 19), (99, 99)])
+        );
+        // unsatisfiable specs are dropped if others remain
+        assert_eq!(
+            evaluate(Some("bytes=5000-6000,0-9"), 100),
+            partial(&[(0, 9)])
+        );
+    }
+
+    #[test]
+    fn merged_ranges() {
+        assert_eq!(
+            evaluate(Some("bytes=50-59,0-9,5-14,15-19,-45"), 100),
+            partial(&[(0, 19), (50, 99)])
+        );
+        assert_eq!(evaluate(Some("bytes=0-9,0-9,2-3"), 100), partial(&[(0, 9)]));
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert_eq!(
+            evaluate(Some("bytes=1000-"), 1000),
+            RangeOutcome::Unsatisfiable
+        );
+        assert_eq!(
+            evaluate(Some("bytes=-0"), 1000),
+            RangeOutcome::Unsatisfiable
+        );
+        assert_eq!(evaluate(Some("bytes=0-"), 0), RangeOutcome::Unsatisfiable);
+        assert_eq!(
+            evaluate(Some("bytes=,1000-,"), 1000),
+            RangeOutcome::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn ignored() {
+        assert_eq!(evaluate(None, 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("items=0-1"), 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("bytes=abc"), 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("bytes=5-1"), 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("bytes=+1-2"), 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("bytes="), 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("bytes=,,"), 1000), RangeOutcome::Full);
+        assert_eq!(evaluate(Some("bytes= , "), 0), RangeOutcome::Full);
+        let many = format!("bytes={}", vec!["0-1"; 100].join(","));
+        assert_eq!(evaluate(Some(&many), 1000), RangeOutcome::Full);
+    }
+
+    #[test]
+    fn multipart_len() {
+        let multipart = Multipart::new("text/plain", 100);
+        let ranges = [
+            ByteRange { start: 0, end: 9 },
+            ByteRange { start: 50, end: 59 },
+        ];
+        let mut body = String::new();
+        for range in &ranges {
+            body.push_str(&multipart.part_header(range));
+            body.push_str(&"x".repeat(range.size() as usize));
+        }
+        body.push_str(&multipart.closing());
+        assert_eq!(multipart.body_len(&ranges), body.len() as u64);
+        assert!(body.contains("Content-Range: bytes 50-59/100\r\n"));
+    }
+}
@@ -1,24 +1,201 @@
-use crate::http::header::Headers;
+use crate::http::chunked::{ChunkedDecoder, ChunkedError};
+use crate::http::header::{HeaderError, Headers};
 use crate::http::helpers::{self, CursorError};
+use crate::http::json::{Json, JsonError};
+use crate::http::router::Rejection;
+use crate::http::status::StatusCode;
 use crate::http::Body;
 use bytes::{Buf, BufMut, BytesMut};
 use std::{
+    future::{poll_fn, Future},
     io::Cursor,
+    pin::Pin,
     str::{self, Utf8Error},
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::Duration,
 };
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::time::{self, Instant, Sleep};
 
-use super::Method;
+use super::{Method, Timeouts, Version};
 
 pub struct Request {
     pub metadata: Metadata,
     pub body: Option<Body>,
+    pub trailers: Trailers,
+    pub params: Params,
+}
+
+impl Request {
+    pub fn new(metadata: Metadata, body: Option<Body>) -> Self {
+        Request {
+            metadata,
+            body,
+            trailers: Trailers::default(),
+            params: Params::default(),
+        }
+    }
+
+    /// Trailer fields sent after a chunked body. They are only known once
+    /// the body has been read to its end.
+    pub fn trailers(&self) -> Option<Headers> {
+        self.trailers.get()
+    }
+
+    /// Value captured by the `:name` or `*name` segment of the matched route.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    /// Takes the body and collects it into memory.
+    pub async fn bytes(&mut self) -> Result<Vec<u8>, BodyError> {
+        match self.body.take() {
+            Some(body) => Ok(body.into_bytes().await?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Takes an `application/x-www-form-urlencoded` body.
+    pub async fn form(&mut self) -> Result<Query, BodyError> {
+        if !self
+            .metadata
+            .media_type()
+            .is_some_and(|mime| mime.eq_ignore_ascii_case("application/x-www-form-urlencoded"))
+        {
+            return Err(BodyError::UnsupportedMediaType);
+        }
+        let data = self.bytes().await?;
+        let form = str::from_utf8(&data).map_err(|_| BodyError::Invalid)?;
+        Ok(Query::parse(form))
+    }
+
+    /// Takes an `application/json` body, also accepting `+json` types.
+    pub async fn json(&mut self) -> Result<Json, BodyError> {
+        let is_json = self.metadata.media_type().is_some_and(|mime| {
+            let mime = mime.to_ascii_lowercase();
+            mime == "application/json"
+                || mime.starts_with("application/") && mime.ends_with("+json")
+        });
+        if !is_json {
+            return Err(BodyError::UnsupportedMediaType);
+        }
+        let data = self.bytes().await?;
+        let text = str::from_utf8(&data).map_err(|_| BodyError::Invalid)?;
+        Ok(Json::parse(text)?)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BodyError {
+    #[error("unsupported content type for the body")]
+    UnsupportedMediaType,
+
+    #[error("body is not valid UTF-8")]
+    Invalid,
+
+    #[error(transparent)]
+    Json(#[from] JsonError),
+
+    #[error("io error while reading the body")]
+    Io(#[from] std::io::Error),
+}
+
+impl BodyError {
+    /// Status to answer a request whose body could not be used with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BodyError::UnsupportedMediaType => StatusCode::UnsupportedMediaType,
+            BodyError::Invalid | BodyError::Json(_) | BodyError::Io(_) => StatusCode::BadRequest,
+        }
+    }
+}
+
+/// Parameters captured while routing, in the order they appear in the path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn pop(&mut self) -> Option<(String, String)> {
+        self.0.pop()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Decoded `name=value` pairs of a query string, in their original order.
+/// A name can appear several times.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query(Vec<(String, String)>);
+
+impl Query {
+    /// Parses `a=1&b=2&a=3`, decoding `+` and `%XX` escapes. Pairs without
+    /// `=` get an empty value; escapes that do not decode are kept verbatim.
+    pub fn parse(query: &str) -> Self {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (form_decode(name), form_decode(value))
+            })
+            .collect();
+        Self(pairs)
+    }
+
+    /// First value for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'q>(&'q self, name: &'q str) -> impl Iterator<Item = &'q str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn form_decode(input: &str) -> String {
+    let input = input.replace('+', " ");
+    helpers::percent_decode(&input).unwrap_or(input)
 }
 
 pub struct Metadata {
     pub method: Method,
+    pub version: Version,
+    /// Percent-decoded path, without the query. This is what routes match on.
     pub path: String,
+    pub query: Query,
+    /// Request-target exactly as it appeared in the request line.
+    pub target: String,
     pub headers: Headers,
 }
 
@@ -26,16 +203,105 @@ impl Metadata {
     pub fn new(method: Method, path: String, headers: Headers) -> Self {
         Metadata {
             method,
+            version: Version::Http11,
+            target: path.clone(),
             path,
+            query: Query::default(),
             headers,
         }
     }
+
+    /// Splits a raw request-target into its decoded path and query.
+    pub fn from_target(
+        method: Method,
+        version: Version,
+        target: String,
+        headers: Headers,
+    ) -> Result<Self, RequestError> {
+        let (raw_path, raw_query) = split_target(&target);
+        let path = helpers::percent_decode(raw_path).ok_or(RequestError::Invalid)?;
+        let query = raw_query.map(Query::parse).unwrap_or_default();
+        Ok(Metadata {
+            method,
+            version,
+            path,
+            query,
+            target,
+            headers,
+        })
+    }
+
+    /// Whether the client wants the connection kept open after the
+    /// response. HTTP/1.1 defaults to keep-alive, HTTP/1.0 to close.
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token("Connection", "close") {
+            return false;
+        }
+        match self.version {
+            Version::Http10 => self.headers.has_token("Connection", "keep-alive"),
+            Version::Http11 => true,
+        }
+    }
+
+    /// Media type of the body from `Content-Type`, without its parameters.
+    pub fn media_type(&self) -> Option<&str> {
+        let content_type = self.headers.get("Content-Type")?;
+        Some(helpers::split_params(content_type).0)
+    }
+
+    /// Path as sent by the client, still percent-encoded.
+    pub fn raw_path(&self) -> &str {
+        split_target(&self.target).0
+    }
+
+    /// Query as sent by the client, without the leading `?`.
+    pub fn raw_query(&self) -> Option<&str> {
+        split_target(&self.target).1
+    }
+}
+
+fn split_target(target: &str) -> (&str, Option<&str>) {
+    // a fragment is never sent, but don't route on one if a client does
+    let target = target.split_once('#').map_or(target, |(target, _)| target);
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
 }
 
 impl Metadata {
-    pub fn validate(cursor: &mut Cursor<&[u8]>) -> Result<(), RequestError> {
-        helpers::get_until_crlf(cursor)?;
-        while !helpers::get_until_crlf(cursor)?.is_empty() {}
+    /// Checks that the buffer holds a complete request head within `limits`,
+    /// failing as soon as a limit is exceeded rather than waiting for more data.
+    pub fn validate(cursor: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), RequestError> {
+        let len = cursor.get_ref().len();
+        let request_line = match helpers::get_until_crlf(cursor) {
+            Ok(line) => line,
+            Err(CursorError::Incomplete) if len > limits.max_request_line + 2 => {
+                return Err(RequestError::UriTooLong)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if request_line.len() > limits.max_request_line {
+            return Err(RequestError::UriTooLong);
+        }
+
+        let headers_start = cursor.position() as usize;
+        let mut count = 0;
+        loop {
+            match helpers::get_until_crlf(cursor) {
+                Ok([]) => break,
+                Ok(_) => count += 1,
+                Err(CursorError::Incomplete) if len - headers_start > limits.max_header_bytes => {
+                    return Err(RequestError::HeadersTooLarge)
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if count > limits.max_headers
+                || cursor.position() as usize - headers_start > limits.max_header_bytes
+            {
+                return Err(RequestError::HeadersTooLarge);
+            }
+        }
         Ok(())
     }
 
@@ -43,13 +309,21 @@ impl Metadata {
         let request_line = str::from_utf8(helpers::get_until_crlf(cursor)?)?;
 
         let mut splitted = request_line.split(' ');
-        let method = match splitted.next().ok_or(RequestError::Invalid)? {
-            "GET" => Method::GET,
-            "POST" => Method::POST,
+        let method = splitted
+            .next()
+            .and_then(Method::from_token)
+            .ok_or(RequestError::Invalid)?;
+
+        let target = splitted.next().ok_or(RequestError::Invalid)?.to_owned();
+        let version = match splitted.next().ok_or(RequestError::Invalid)? {
+            "HTTP/1.0" => Version::Http10,
+            // later 1.x minor versions are compatible with 1.1
+            version if version.starts_with("HTTP/1.") => Version::Http11,
             _ => return Err(RequestError::Invalid),
         };
-
-        let path = splitted.next().ok_or(RequestError::Invalid)?.to_owned();
+        if splitted.next().is_some() {
+            return Err(RequestError::Invalid);
+        }
 
         let mut headers = Headers::new();
         loop {
@@ -57,17 +331,49 @@ impl Metadata {
             if header_line.is_empty() {
                 break;
             }
-            let (key, value) = header_line.split_once(':').ok_or(RequestError::Invalid)?;
-            let value = value.trim();
-            headers.insert(key.to_owned(), value.to_owned());
+            headers.insert_header_line(header_line)?;
         }
 
-        Ok(Metadata::new(method, path, headers))
+        Metadata::from_target(method, version, target, headers)
+    }
+}
+
+/// Bounds on what a single request may make the parser buffer.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Longest request line, without the CRLF.
+    pub max_request_line: usize,
+    /// Most header fields in the request head.
+    pub max_headers: usize,
+    /// Most bytes of header fields, including their line breaks.
+    pub max_header_bytes: usize,
+    /// Largest body, both for `Content-Length` and decoded chunked bodies.
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 16 * 1024,
+            max_body: 16 * 1024 * 1024,
+        }
     }
 }
 
+/// How the body of a request is delimited.
+#[derive(Debug, PartialEq)]
+pub enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
 pub struct RequestParser {
     buf: BytesMut,
+    limits: Limits,
+    timeouts: Timeouts,
 }
 
 #[derive(Error, Debug)]
@@ -77,6 +383,42 @@ pub enum RequestError {
 
     #[error("invalid request")]
     Invalid,
+
+    #[error(transparent)]
+    Chunked(#[from] ChunkedError),
+
+    #[error("request line too long")]
+    UriTooLong,
+
+    #[error("request header fields too large")]
+    HeadersTooLarge,
+
+    #[error("request body too large")]
+    BodyTooLarge,
+
+    #[error("unsupported expectation")]
+    ExpectationFailed,
+
+    #[error("unsupported transfer coding")]
+    UnsupportedTransferEncoding,
+}
+
+impl RequestError {
+    /// Status to answer the client with before closing the connection.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::Incomplete => None,
+            RequestError::HeadersTooLarge
+            | RequestError::Chunked(ChunkedError::TrailersTooLarge) => {
+                Some(StatusCode::RequestHeaderFieldsTooLarge)
+            }
+            RequestError::Invalid | RequestError::Chunked(_) => Some(StatusCode::BadRequest),
+            RequestError::UriTooLong => Some(StatusCode::UriTooLong),
+            RequestError::BodyTooLarge => Some(StatusCode::ContentTooLarge),
+            RequestError::ExpectationFailed => Some(StatusCode::ExpectationFailed),
+            RequestError::UnsupportedTransferEncoding => Some(StatusCode::NotImplemented),
+        }
+    }
 }
 
 impl From<CursorError> for RequestError {
@@ -88,89 +430,259 @@ impl From<CursorError> for RequestError {
     }
 }
 
+impl From<HeaderError> for RequestError {
+    fn from(_: HeaderError) -> Self {
+        RequestError::Invalid
+    }
+}
+
 impl From<Utf8Error> for RequestError {
     fn from(_: Utf8Error) -> Self {
         RequestError::Invalid
     }
 }
 
+/// How much of a request body is left and how it is delimited.
+pub struct BodyProgress {
+    state: BodyState,
+    received: usize,
+    trailers: Option<Headers>,
+}
+
+enum BodyState {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+    Done,
+}
+
+impl BodyProgress {
+    /// Progress of a body delimited by `framing`. Trailers of a chunked body
+    /// are held to the same limits as the request head.
+    pub fn new(framing: Framing, limits: &Limits) -> Self {
+        let state = match framing {
+            Framing::None | Framing::Length(0) => BodyState::Done,
+            Framing::Length(length) => BodyState::Length(length),
+            Framing::Chunked => BodyState::Chunked(ChunkedDecoder::with_limits(
+                limits.max_header_bytes,
+                limits.max_headers,
+            )),
+        };
+        Self {
+            state,
+            received: 0,
+            trailers: None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, BodyState::Done)
+    }
+
+    /// Trailer fields of a chunked body, once all of it has been read.
+    pub fn take_trailers(&mut self) -> Option<Headers> {
+        self.trailers.take()
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum RequestParserError {
     #[error("reader did not produce a complete request")]
     Disconnect,
 
+    #[error("reader ended before the end of the request body")]
+    TruncatedBody,
+
     #[error("io error from reader")]
     Io(#[from] std::io::Error),
 
+    #[error("no new request before the keep-alive timeout")]
+    IdleTimeout,
+
+    #[error("request was not received in time")]
+    Timeout,
+
     #[error(transparent)]
     RequestError(#[from] RequestError),
+
+    #[error("request rejected before reading its body")]
+    Rejected(Rejection),
+}
+
+impl RequestParserError {
+    /// Status to answer the client with before closing the connection.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestParserError::RequestError(e) => e.status(),
+            RequestParserError::Rejected(rejection) => Some(rejection.status),
+            RequestParserError::Timeout => Some(StatusCode::RequestTimeout),
+            _ => None,
+        }
+    }
+
+    /// Headers to send along with [`RequestParserError::status`].
+    pub fn headers(&self) -> Headers {
+        match self {
+            RequestParserError::Rejected(rejection) => rejection.headers.clone(),
+            _ => Headers::new(),
+        }
+    }
+}
+
+impl Default for RequestParser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RequestParser {
     pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
         Self {
             buf: BytesMut::with_capacity(2 * 1024),
+            limits,
+            timeouts: Timeouts::default(),
         }
     }
 
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
     fn put<B: Buf>(&mut self, src: B) {
         self.buf.put(src);
     }
 
     pub fn metadata_from_buffer(&mut self) -> Result<Metadata, RequestError> {
         let mut cursor = Cursor::new(&self.buf[..]);
-        Metadata::validate(&mut cursor)?;
+        Metadata::validate(&mut cursor, &self.limits)?;
         cursor.set_position(0);
         let metadata = Metadata::parse(&mut cursor)?;
         self.buf.advance(cursor.position() as usize);
         Ok(metadata)
     }
 
-    pub async fn read_request<R>(&mut self, reader: &mut R) -> Result<Request, RequestParserError>
+    /// Reads the request head, leaving the body in the reader so the caller
+    /// can inspect the request first, e.g. to answer `Expect: 100-continue`.
+    pub async fn read_metadata<R>(&mut self, reader: &mut R) -> Result<Metadata, RequestParserError>
     where
         R: AsyncRead + Unpin,
     {
-        let metadata;
+        // the whole head has to arrive within the timeout, counted from its first byte
+        let mut head_deadline = None;
         loop {
             match self.metadata_from_buffer() {
-                Ok(md) => {
-                    metadata = md;
-                    break;
-                }
+                Ok(metadata) => return Ok(metadata),
                 Err(RequestError::Incomplete) => {
-                    if 0 == reader.read_buf(&mut self.buf).await? {
+                    let read = if self.buf.is_empty() {
+                        time::timeout(self.timeouts.idle, reader.read_buf(&mut self.buf))
+                            .await
+                            .map_err(|_| RequestParserError::IdleTimeout)?
+                    } else {
+                        let deadline = *head_deadline
+                            .get_or_insert_with(|| Instant::now() + self.timeouts.header_read);
+                        time::timeout_at(deadline, reader.read_buf(&mut self.buf))
+                            .await
+                            .map_err(|_| RequestParserError::Timeout)?
+                    };
+                    if 0 == read? {
                         return Err(RequestParserError::Disconnect);
                     }
                 }
                 Err(e) => return Err(e.into()),
             }
         }
+    }
 
-        if metadata.method == Method::GET {
-            return Ok(Request {
-                metadata,
-                body: None,
-            });
+    /// Works out how the body of the request is delimited, rejecting bodies
+    /// that are malformed or declared larger than the limit.
+    pub fn framing(&self, metadata: &Metadata) -> Result<Framing, RequestError> {
+        if metadata.headers.contains("Transfer-Encoding") {
+            // with both, the two ends could disagree on where the body ends
+            if metadata.headers.contains("Content-Length") {
+                return Err(RequestError::Invalid);
+            }
+            // other codings would have to be undone before the body is usable
+            let codings: Vec<&str> = metadata
+                .headers
+                .get_all("Transfer-Encoding")
+                .flat_map(|encoding| encoding.split(','))
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty())
+                .collect();
+            if codings
+                .iter()
+                .any(|coding| !coding.eq_ignore_ascii_case("chunked"))
+            {
+                return Err(RequestError::UnsupportedTransferEncoding);
+            }
+            if codings.len() != 1 {
+                return Err(RequestError::Invalid);
+            }
+            return Ok(Framing::Chunked);
         }
 
-        let content_length: usize = metadata
+        // without framing headers a request has no body, whatever the method
+        let length = match metadata.headers.get("Content-Length") {
+            Some(length) => length,
+            None => return Ok(Framing::None),
+        };
+        // repeated fields are only fine if they all agree
+        if metadata
             .headers
-            .get("Content-Length")
-            .ok_or(RequestError::Invalid)?
-            .parse()
-            .map_err(|_| RequestError::Invalid)?;
+            .get_all("Content-Length")
+            .any(|l| l != length)
+        {
+            return Err(RequestError::Invalid);
+        }
+        // digits only, `parse` would also take a sign
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RequestError::Invalid);
+        }
+        let length = length.parse().map_err(|_| RequestError::Invalid)?;
+        if length > self.limits.max_body {
+            return Err(RequestError::BodyTooLarge);
+        }
+        Ok(Framing::Length(length))
+    }
 
-        let mut remaining = self.buf.remaining();
-        // timeout needed...
-        while remaining < content_length {
-            remaining += reader.read_buf(&mut self.buf).await?;
+    /// Moves the part of the body that is already buffered to `out`.
+    fn body_data(
+        &mut self,
+        progress: &mut BodyProgress,
+        out: &mut Vec<u8>,
+    ) -> Result<(), RequestError> {
+        let before = out.len();
+        match &mut progress.state {
+            BodyState::Length(remaining) => {
+                let n = (*remaining).min(self.buf.len());
+                out.extend_from_slice(&self.buf[..n]);
+                self.buf.advance(n);
+                *remaining -= n;
+                if *remaining == 0 {
+                    progress.state = BodyState::Done;
+                }
+            }
+            BodyState::Chunked(decoder) => {
+                if decoder.decode(&mut self.buf, out)? {
+                    if let BodyState::Chunked(decoder) =
+                        std::mem::replace(&mut progress.state, BodyState::Done)
+                    {
+                        progress.trailers = Some(decoder.into_trailers());
+                    }
+                }
+            }
+            BodyState::Done => {}
         }
-        let data = self.buf.copy_to_bytes(content_length).to_vec();
 
-        return Ok(Request {
-            metadata,
-            body: Some(Body {data} ),
-        });
+        progress.received += out.len() - before;
+        if progress.received > self.limits.max_body {
+            return Err(RequestError::BodyTooLarge);
+        }
+        Ok(())
     }
 
     pub fn buffer_is_empty(&self) -> bool {
@@ -178,6 +690,223 @@ impl RequestParser {
     }
 }
 
+const BODY_READ_SIZE: usize = 8 * 1024;
+
+/// Read side of a connection: the stream, what the parser has buffered from
+/// it and how far the body of the current request has been read.
+pub struct Incoming<R> {
+    pub reader: R,
+    pub parser: RequestParser,
+    body: BodyProgress,
+    body_started: Instant,
+    deadline: Option<Pin<Box<Sleep>>>,
+    error: Option<RequestParserError>,
+}
+
+impl<R> Incoming<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(reader: R, parser: RequestParser) -> Self {
+        Self {
+            body: BodyProgress::new(Framing::None, &parser.limits),
+            reader,
+            parser,
+            body_started: Instant::now(),
+            deadline: None,
+            error: None,
+        }
+    }
+
+    pub async fn read_metadata(&mut self) -> Result<Metadata, RequestParserError> {
+        self.parser.read_metadata(&mut self.reader).await
+    }
+
+    /// Starts on a new body delimited by `framing`.
+    pub fn start_body(&mut self, framing: Framing) {
+        self.body = BodyProgress::new(framing, &self.parser.limits);
+        self.body_started = Instant::now();
+        self.deadline = None;
+        self.error = None;
+    }
+
+    /// Trailer fields of the current body, once all of it has been read.
+    pub fn take_trailers(&mut self) -> Option<Headers> {
+        self.body.take_trailers()
+    }
+
+    /// The error that made the current body unreadable, if any.
+    pub fn take_error(&mut self) -> Option<RequestParserError> {
+        self.error.take()
+    }
+
+    /// Skips whatever the handler left unread of the current body, so that
+    /// the next request on the connection can be parsed.
+    pub async fn finish_body(&mut self) -> Result<(), RequestParserError> {
+        let mut scratch = Vec::new();
+        loop {
+            scratch.clear();
+            let result = poll_fn(|cx| self.poll_body(cx, &mut scratch)).await;
+            if let Some(e) = self.error.take() {
+                return Err(e);
+            }
+            result?;
+            if scratch.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Appends the next piece of the body to `out`, reading from the stream
+    /// if nothing is buffered. Leaves `out` untouched at the end of the body.
+    fn poll_body(&mut self, cx: &mut Context<'_>, out: &mut Vec<u8>) -> Poll<std::io::Result<()>> {
+        if self.error.is_some() {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request body could not be read",
+            )));
+        }
+        loop {
+            let before = out.len();
+            if let Err(e) = self.parser.body_data(&mut self.body, out) {
+                return Poll::Ready(Err(self.fail(e.into())));
+            }
+            if out.len() > before || self.body.is_done() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0; BODY_READ_SIZE];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut self.reader).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                    return Poll::Ready(Err(self.fail(RequestParserError::TruncatedBody)));
+                }
+                Poll::Ready(Ok(())) => {
+                    self.parser.put(read_buf.filled());
+                    self.deadline = None;
+                }
+                Poll::Ready(Err(e)) => {
+                    let err = std::io::Error::new(e.kind(), e.to_string());
+                    self.error = Some(e.into());
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => {
+                    let until = self.body_deadline();
+                    let deadline = self
+                        .deadline
+                        .get_or_insert_with(|| Box::pin(time::sleep_until(until)));
+                    ready!(deadline.as_mut().poll(cx));
+                    return Poll::Ready(Err(self.fail(RequestParserError::Timeout)));
+                }
+            }
+        }
+    }
+
+    /// When the body times out if nothing more arrives: after `body_read` of
+    /// silence, or once the body falls behind `body_min_rate`.
+    fn body_deadline(&self) -> Instant {
+        let timeouts = &self.parser.timeouts;
+        let silence = Instant::now() + timeouts.body_read;
+        if timeouts.body_min_rate == 0 {
+            return silence;
+        }
+        let earned =
+            Duration::from_secs_f64(self.body.received as f64 / timeouts.body_min_rate as f64);
+        silence.min(self.body_started + timeouts.body_read + earned)
+    }
+
+    fn fail(&mut self, e: RequestParserError) -> std::io::Error {
+        let kind = match e {
+            RequestParserError::Timeout => std::io::ErrorKind::TimedOut,
+            RequestParserError::TruncatedBody => std::io::ErrorKind::UnexpectedEof,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        let err = std::io::Error::new(kind, e.to_string());
+        self.error = Some(e);
+        err
+    }
+}
+
+/// The read side of a connection while it is lent to a handler. The
+/// connection takes it back once the response is written.
+pub type SharedIncoming<R> = Arc<Mutex<Option<Incoming<R>>>>;
+
+/// Trailer fields of a request body, shared between the request and the
+/// stream that reads the body.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers(Arc<Mutex<Option<Headers>>>);
+
+impl Trailers {
+    pub fn get(&self) -> Option<Headers> {
+        self.lock().clone()
+    }
+
+    fn set(&self, trailers: Headers) {
+        *self.lock() = Some(trailers);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Headers>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Request body read from the connection as the handler consumes it.
+pub struct BodyStream<R> {
+    incoming: SharedIncoming<R>,
+    data: Vec<u8>,
+    pos: usize,
+    trailers: Trailers,
+}
+
+impl<R> BodyStream<R> {
+    pub fn new(incoming: SharedIncoming<R>) -> Self {
+        Self {
+            incoming,
+            data: Vec::new(),
+            pos: 0,
+            trailers: Trailers::default(),
+        }
+    }
+
+    /// Where the trailer fields show up once the body has been read.
+    pub fn trailers(&self) -> Trailers {
+        self.trailers.clone()
+    }
+}
+
+impl<R> AsyncRead for BodyStream<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos == this.data.len() {
+            this.data.clear();
+            this.pos = 0;
+            let mut guard = this.incoming.lock().unwrap_or_else(PoisonError::into_inner);
+            let incoming = match guard.as_mut() {
+                Some(incoming) => incoming,
+                None => {
+                    return Poll::Ready(Err(std::io::Error::other("request was already answered")))
+                }
+            };
+            ready!(incoming.poll_body(cx, &mut this.data))?;
+            if let Some(trailers) = incoming.take_trailers() {
+                this.trailers.set(trailers);
+            }
+        }
+
+        let n = buf.remaining().min(this.data.len() - this.pos);
+        buf.put_slice(&this.data[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
@@ -196,6 +925,96 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn query_and_decoding() -> Result<(), anyhow::Error> {
+        let mut parser = RequestParser::new();
+        parser.put(&b"GET /echo/a%20b%2Fc?x=1&y=two+words&x=%263&flag HTTP/1.1\r\n\r\n"[..]);
+        let metadata = parser.metadata_from_buffer()?;
+        assert_eq!(metadata.path, "/echo/a b/c");
+        assert_eq!(metadata.raw_path(), "/echo/a%20b%2Fc");
+        assert_eq!(metadata.raw_query(), Some("x=1&y=two+words&x=%263&flag"));
+        assert_eq!(metadata.query.get_all("x").collect::<Vec<_>>(), ["1", "&3"]);
+        assert_eq!(metadata.query.get("y"), Some("two words"));
+        assert_eq!(metadata.query.get("flag"), Some(""));
+        assert_eq!(metadata.query.get("z"), None);
+
+        let mut parser = RequestParser::new();
+        parser.put(&b"GET /a%zz HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::Invalid)
+        ));
+        Ok(())
+    }
+
+    fn post(content_type: &str, body: &str) -> Request {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type".to_string(), content_type.to_string());
+        let metadata = Metadata::new(Method::POST, "/".to_string(), headers);
+        let body = Body::from_reader(std::io::Cursor::new(body.to_string()), None);
+        Request::new(metadata, Some(body))
+    }
+
+    #[tokio::test]
+    async fn typed_bodies() -> Result<(), anyhow::Error> {
+        let mut request = post(
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            "name=a+b&tag=%2Fx",
+        );
+        let form = request.form().await?;
+        assert_eq!(form.get("name"), Some("a b"));
+        assert_eq!(form.get("tag"), Some("/x"));
+
+        let mut request = post("application/vnd.api+json", r#"{"id": 7}"#);
+        let json = request.json().await?;
+        assert_eq!(json.get("id").and_then(Json::as_i64), Some(7));
+
+        let status = post("text/plain", "{}")
+            .json()
+            .await
+            .err()
+            .map(|e| e.status());
+        assert_eq!(status, Some(StatusCode::UnsupportedMediaType));
+        let status = post("application/json", "{}")
+            .form()
+            .await
+            .err()
+            .map(|e| e.status());
+        assert_eq!(status, Some(StatusCode::UnsupportedMediaType));
+        let status = post("application/json", "{")
+            .json()
+            .await
+            .err()
+            .map(|e| e.status());
+        assert_eq!(status, Some(StatusCode::BadRequest));
+        Ok(())
+    }
+
+    #[test]
+    fn version() -> Result<(), anyhow::Error> {
+        let mut parser = RequestParser::new();
+        parser.put(&b"GET / HTTP/1.0\r\n\r\n"[..]);
+        let metadata = parser.metadata_from_buffer()?;
+        assert_eq!(metadata.version, Version::Http10);
+        assert!(!metadata.keep_alive());
+
+        let mut parser = RequestParser::new();
+        parser.put(&b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"[..]);
+        let metadata = parser.metadata_from_buffer()?;
+        assert_eq!(metadata.version, Version::Http11);
+        assert!(!metadata.keep_alive());
+
+        for line in ["GET /\r\n\r\n", "GET / HTTP/2.0\r\n\r\n"] {
+            let mut parser = RequestParser::new();
+            parser.put(line.as_bytes());
+            assert!(matches!(
+                parser.metadata_from_buffer(),
+                Err(RequestError::Invalid)
+            ));
+        }
+        Ok(())
+    }
+
     #[test]
     fn no_headers() -> Result<(), anyhow::Error> {
         let mut parser = RequestParser::new();
@@ -204,4 +1023,253 @@ mod tests {
         assert!(metadata.headers.is_empty());
         Ok(())
     }
+
+    #[test]
+    fn methods() -> Result<(), anyhow::Error> {
+        let cases = [
+            ("GET", Method::GET),
+            ("DELETE", Method::DELETE),
+            ("OPTIONS", Method::OPTIONS),
+            ("PROPFIND", Method::Extension("PROPFIND".to_string())),
+        ];
+        for (token, method) in cases {
+            let mut parser = RequestParser::new();
+            parser.put(format!("{token} / HTTP/1.1\r\n\r\n").as_bytes());
+            assert_eq!(parser.metadata_from_buffer()?.method, method);
+        }
+
+        let mut parser = RequestParser::new();
+        parser.put(&b"GE(T / HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::Invalid)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_headers() -> Result<(), anyhow::Error> {
+        let mut parser = RequestParser::new();
+        parser.put(&b"GET / HTTP/1.1\r\naccept: text/html\r\nAccept: */*\r\n\r\n"[..]);
+        let metadata = parser.metadata_from_buffer()?;
+        assert_eq!(
+            metadata.headers.get_all("Accept").collect::<Vec<_>>(),
+            ["text/html", "*/*"]
+        );
+
+        let mut parser = RequestParser::new();
+        parser.put(&b"GET / HTTP/1.1\r\nbroken header\r\n\r\n"[..]);
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::Invalid)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn content_length_digits() -> Result<(), anyhow::Error> {
+        let framing = |length: &str| {
+            let mut parser = RequestParser::new();
+            parser.put(format!("POST / HTTP/1.1\r\nContent-Length: {length}\r\n\r\n").as_bytes());
+            let metadata = parser.metadata_from_buffer()?;
+            parser.framing(&metadata)
+        };
+        assert_eq!(framing("5")?, Framing::Length(5));
+        for length in ["+5", "-5", "5 5", "0x5", "5.0"] {
+            assert!(
+                matches!(framing(length), Err(RequestError::Invalid)),
+                "{length} was accepted"
+            );
+        }
+        Ok(())
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_bytes: 64,
+            max_body: 8,
+        }
+    }
+
+    #[test]
+    fn request_line_limit() {
+        let mut parser = RequestParser::with_limits(limits());
+        parser.put(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32)).as_bytes());
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::UriTooLong)
+        ));
+
+        // no need to wait for the end of the line
+        let mut parser = RequestParser::with_limits(limits());
+        parser.put(format!("GET /{}", "a".repeat(64)).as_bytes());
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::UriTooLong)
+        ));
+    }
+
+    #[test]
+    fn header_limits() {
+        let mut parser = RequestParser::with_limits(limits());
+        parser.put(&b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"[..]);
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::HeadersTooLarge)
+        ));
+
+        let mut parser = RequestParser::with_limits(limits());
+        parser.put(format!("GET / HTTP/1.1\r\nA: {}", "a".repeat(64)).as_bytes());
+        assert!(matches!(
+            parser.metadata_from_buffer(),
+            Err(RequestError::HeadersTooLarge)
+        ));
+    }
+
+    /// Reads the request in `input` the way a connection does, returning
+    /// its body, trailers and what is left of the read side.
+    async fn read_body(
+        parser: RequestParser,
+        input: &'static [u8],
+    ) -> Result<(Vec<u8>, Option<Headers>, Incoming<&'static [u8]>), RequestParserError> {
+        let mut incoming = Incoming::new(input, parser);
+        let metadata = incoming.read_metadata().await?;
+        let framing = incoming.parser.framing(&metadata)?;
+        incoming.start_body(framing);
+
+        let lent = Arc::new(Mutex::new(Some(incoming)));
+        let mut stream = BodyStream::new(lent.clone());
+        let trailers = stream.trailers();
+        let mut body = Vec::new();
+        let read = time::timeout(Duration::from_secs(1), stream.read_to_end(&mut body))
+            .await
+            .expect("body stream kept waiting on a closed reader");
+        let mut incoming = lent.lock().unwrap().take().unwrap();
+        if let Some(e) = incoming.take_error() {
+            return Err(e);
+        }
+        read?;
+        Ok((body, trailers.get(), incoming))
+    }
+
+    #[tokio::test]
+    async fn body_limit() {
+        let input = &b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"[..];
+        let result = read_body(RequestParser::with_limits(limits()), input).await;
+        assert!(matches!(
+            result,
+            Err(RequestParserError::RequestError(RequestError::BodyTooLarge))
+        ));
+
+        let input = &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n"[..];
+        let result = read_body(RequestParser::with_limits(limits()), input).await;
+        assert!(matches!(
+            result,
+            Err(RequestParserError::RequestError(RequestError::BodyTooLarge))
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_body() {
+        let inputs: [&'static [u8]; 3] = [
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab",
+        ];
+        for input in inputs {
+            let result = read_body(RequestParser::new(), input).await;
+            assert!(matches!(result, Err(RequestParserError::TruncatedBody)));
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_body() -> Result<(), anyhow::Error> {
+        let input = &b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext\r\n, world\r\n0\r\nChecksum: abc\r\n\r\n"[..];
+        let (body, trailers, incoming) = read_body(RequestParser::new(), input).await?;
+        assert_eq!(body, b"hello, world".to_vec());
+        assert_eq!(trailers.unwrap().get("Checksum"), Some("abc"));
+        assert!(incoming.parser.buffer_is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunked_malformed() {
+        let input = &b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n"[..];
+        let result = read_body(RequestParser::new(), input).await;
+        assert!(matches!(
+            result,
+            Err(RequestParserError::RequestError(RequestError::Chunked(
+                ChunkedError::InvalidSize
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn trailer_limits() {
+        // the trailer line never ends, so this only stops at the limit
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX: \
+            aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let result = read_body(RequestParser::with_limits(limits()), input).await;
+        let Err(RequestParserError::RequestError(e)) = result else {
+            panic!("oversized trailer was accepted");
+        };
+        assert!(matches!(
+            e,
+            RequestError::Chunked(ChunkedError::TrailersTooLarge)
+        ));
+        assert_eq!(e.status(), Some(StatusCode::RequestHeaderFieldsTooLarge));
+
+        let input = &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            0\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"[..];
+        let result = read_body(RequestParser::with_limits(limits()), input).await;
+        assert!(matches!(
+            result,
+            Err(RequestParserError::RequestError(RequestError::Chunked(
+                ChunkedError::TrailersTooLarge
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsupported_transfer_encoding() {
+        let inputs: [&'static [u8]; 2] = [
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+        ];
+        for input in inputs {
+            let result = read_body(RequestParser::new(), input).await;
+            assert!(matches!(
+                result,
+                Err(RequestParserError::RequestError(
+                    RequestError::UnsupportedTransferEncoding
+                ))
+            ));
+        }
+        assert_eq!(
+            RequestError::UnsupportedTransferEncoding.status(),
+            Some(StatusCode::NotImplemented)
+        );
+    }
+
+    #[tokio::test]
+    async fn ambiguous_framing() {
+        let inputs: [&'static [u8]; 3] = [
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+              Content-Length: 3\r\n\r\n0\r\n\r\n",
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+              Transfer-Encoding: chunked\r\n\r\n",
+        ];
+        for input in inputs {
+            let result = read_body(RequestParser::new(), input).await;
+            assert!(matches!(
+                result,
+                Err(RequestParserError::RequestError(RequestError::Invalid))
+            ));
+        }
+    }
 }
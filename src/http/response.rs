@@ -1,4 +1,6 @@
 use crate::http::header::Headers;
+use crate::http::helpers;
+use crate::http::json::Json;
 use crate::http::status::StatusCode;
 use crate::http::Body;
 
@@ -9,23 +11,360 @@ pub struct Response {
 }
 
 impl Response {
+    /// Starts a `200 OK` response without headers or body.
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            status: StatusCode::Ok,
+            headers: Headers::new(),
+            body: None,
+        }
+    }
+
     pub fn from_data(status: StatusCode, headers: Headers, data: Vec<u8>) -> Self {
+        Self::from_body(status, headers, Body::Full(data))
+    }
+
+    pub fn from_body(status: StatusCode, headers: Headers, body: Body) -> Self {
         Self {
             status,
             headers: Some(headers),
-            body: Some(Body { data }),
+            body: Some(body),
         }
     }
 
+    /// Response with `json` as its body.
+    pub fn from_json(status: StatusCode, json: &Json) -> Self {
+        Self::json_text(status, json.to_string())
+    }
+
+    /// Response with `text` as its body, which must already be JSON.
+    pub fn json_text(status: StatusCode, text: String) -> Self {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type".to_string(), "application/json".to_string());
+        Self::from_data(status, headers, text.into_bytes())
+    }
+
+    /// Sends the client to `location`, a path or an absolute URL. Control
+    /// characters in it are dropped.
+    pub fn redirect(redirect: Redirect, location: &str) -> Self {
+        let mut response = Self::from_status(redirect.status());
+        let headers = response.headers.get_or_insert_with(Headers::new);
+        headers.insert("Location".to_string(), helpers::field_value(location));
+        response
+    }
+
+    /// Response with the status line as a plain text body, or no body for
+    /// statuses that cannot have one.
     pub fn from_status(status: StatusCode) -> Self {
+        if !status.allows_body() {
+            return Self {
+                status,
+                headers: Some(Headers::new()),
+                body: None,
+            };
+        }
         let mut headers = Headers::new();
-        let status_text = status.to_string();
+        let status_text = status.to_string().trim_end().to_string();
         headers.insert("Content-Type".to_string(), "text/plain".to_string());
         let data = status_text.into_bytes();
         Self {
             status,
             headers: Some(headers),
-            body: Some(Body { data }),
+            body: Some(Body::Full(data)),
+        }
+    }
+}
+
+/// Kinds of redirect, all of which keep the request method except
+/// [`Redirect::SeeOther`], which makes the client follow up with GET.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Redirect {
+    /// `308 Permanent Redirect`
+    Permanent,
+    /// `307 Temporary Redirect`
+    Temporary,
+    /// `303 See Other`, e.g. after a form was posted.
+    SeeOther,
+}
+
+impl Redirect {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Redirect::Permanent => StatusCode::PermanentRedirect,
+            Redirect::Temporary => StatusCode::TemporaryRedirect,
+            Redirect::SeeOther => StatusCode::SeeOther,
+        }
+    }
+}
+
+/// Builds a [`Response`] step by step, see [`Response::builder`].
+pub struct ResponseBuilder {
+    status: StatusCode,
+    headers: Headers,
+    body: Option<Body>,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Sets the header `name`, replacing any earlier value. Control
+    /// characters are dropped from `value`, and a `name` that is not a valid
+    /// token is ignored, so neither can inject fields into the response.
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        if !name.is_empty() && name.bytes().all(helpers::is_tchar) {
+            let value = helpers::field_value(&value.into());
+            self.headers.insert(name.to_string(), value);
+        }
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Sets a UTF-8 text body and its content type.
+    pub fn text(self, text: impl Into<String>) -> Response {
+        self.typed_body("text/plain; charset=utf-8", text.into())
+    }
+
+    /// Sets an HTML body and its content type.
+    pub fn html(self, html: impl Into<String>) -> Response {
+        self.typed_body("text/html; charset=utf-8", html.into())
+    }
+
+    pub fn json(self, json: &Json) -> Response {
+        self.typed_body("application/json", json.to_string())
+    }
+
+    fn typed_body(self, content_type: &str, body: String) -> Response {
+        self.header("Content-Type", content_type)
+            .body(body.into_bytes())
+            .build()
+    }
+
+    /// Finishes the response. Without a body it gets an empty one, unless
+    /// the status does not allow any.
+    pub fn build(self) -> Response {
+        let body = match self.body {
+            Some(body) if self.status.allows_body() => Some(body),
+            None if self.status.allows_body() => Some(Body::Full(Vec::new())),
+            _ => None,
+        };
+        Response {
+            status: self.status,
+            headers: Some(self.headers),
+            body,
+        }
+    }
+}
+
+// describe a body, so they go when a status drops it
+const ENTITY_HEADERS: [&str; 4] = [
+    "Content-Type",
+    "Content-Length",
+    "Content-Encoding",
+    "Content-Range",
+];
+
+/// Anything a handler can answer with.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+
+    /// The response with `status` in place of its own, without a body if
+    /// `status` does not allow one.
+    fn into_response_with_status(self, status: StatusCode) -> Response
+    where
+        Self: Sized,
+    {
+        let mut response = self.into_response();
+        response.status = status;
+        if !status.allows_body() {
+            response.body = None;
+            if let Some(headers) = response.headers.as_mut() {
+                for name in ENTITY_HEADERS {
+                    headers.remove(name);
+                }
+            }
+        }
+        response
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for ResponseBuilder {
+    fn into_response(self) -> Response {
+        self.build()
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        Response::from_status(self)
+    }
+
+    // the body would describe the replaced status
+    fn into_response_with_status(self, status: StatusCode) -> Response {
+        Response::from_status(status)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::builder().text(self)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::builder().text(self)
+    }
+}
+
+impl IntoResponse for Json {
+    fn into_response(self) -> Response {
+        Response::builder().json(&self)
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .body(self)
+            .build()
+    }
+}
+
+/// The response of `T` with its status replaced.
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        let (status, inner) = self;
+        inner.into_response_with_status(status)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(ok) => ok.into_response(),
+            Err(err) => err.into_response(),
         }
     }
+
+    fn into_response_with_status(self, status: StatusCode) -> Response {
+        match self {
+            Ok(ok) => ok.into_response_with_status(status),
+            Err(err) => err.into_response_with_status(status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn body(response: &Response) -> &[u8] {
+        match &response.body {
+            Some(Body::Full(data)) => data,
+            _ => &[],
+        }
+    }
+
+    fn header<'r>(response: &'r Response, name: &str) -> Option<&'r str> {
+        response.headers.as_ref()?.get(name)
+    }
+
+    #[test]
+    fn builder() {
+        let response = Response::builder()
+            .status(StatusCode::Created)
+            .header("X-Id", "7")
+            .json(&Json::object().insert("id", 7));
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(header(&response, "X-Id"), Some("7"));
+        assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+        assert_eq!(body(&response), b"{\"id\":7}");
+
+        let response = Response::builder().build();
+        assert_eq!(response.body.as_ref().and_then(Body::len), Some(0));
+        let response = Response::builder()
+            .status(StatusCode::NoContent)
+            .body(b"dropped".to_vec())
+            .build();
+        assert!(response.body.is_none());
+    }
+
+    #[test]
+    fn header_injection() {
+        let response = Response::builder()
+            .header("X-Id", "7\r\nSet-Cookie: a=b")
+            .header("X-Bad\r\nSet-Cookie", "a=b")
+            .header("", "empty")
+            .build();
+        let headers = response.headers.as_ref().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("X-Id"), Some("7Set-Cookie: a=b"));
+
+        let response = Response::redirect(Redirect::Temporary, "/next\r\n\r\n<p>");
+        assert_eq!(header(&response, "Location"), Some("/next<p>"));
+    }
+
+    #[test]
+    fn redirects() {
+        let response = Response::redirect(Redirect::SeeOther, "/done");
+        assert_eq!(response.status, StatusCode::SeeOther);
+        assert_eq!(header(&response, "Location"), Some("/done"));
+        let status = Response::redirect(Redirect::Permanent, "/").status;
+        assert_eq!(status, StatusCode::PermanentRedirect);
+    }
+
+    #[test]
+    fn into_response() {
+        let response = "hi".into_response();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(body(&response), b"hi");
+
+        let response = (StatusCode::Accepted, "queued".to_string()).into_response();
+        assert_eq!(response.status, StatusCode::Accepted);
+        assert_eq!(body(&response), b"queued");
+
+        let result: Result<Json, StatusCode> = Err(StatusCode::Forbidden);
+        let response = result.into_response();
+        assert_eq!(response.status, StatusCode::Forbidden);
+        assert_eq!(body(&response), b"403 Forbidden");
+    }
+
+    #[test]
+    fn replaced_status() {
+        let response = (StatusCode::Created, StatusCode::NotFound).into_response();
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(body(&response), b"201 Created");
+        let result: Result<Json, StatusCode> = Err(StatusCode::NotFound);
+        let response = (StatusCode::Gone, result).into_response();
+        assert_eq!(body(&response), b"410 Gone");
+
+        // a status without a body takes the headers about the body too
+        let response = (StatusCode::NoContent, "dropped").into_response();
+        assert_eq!(response.status, StatusCode::NoContent);
+        assert!(response.body.is_none());
+        assert_eq!(header(&response, "Content-Type"), None);
+        let response = (StatusCode::NotModified, StatusCode::Ok).into_response();
+        assert!(response.body.is_none());
+        assert_eq!(header(&response, "Content-Type"), None);
+    }
 }
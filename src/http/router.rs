@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
-use crate::http::request::Request;
-use crate::http::response::Response;
+use crate::http::header::Headers;
+use crate::http::request::{Params, Request};
+use crate::http::response::{IntoResponse, Response};
+use crate::http::status::StatusCode;
 use crate::http::State;
 
 use super::{handlers, Method};
@@ -18,46 +20,191 @@ struct HandlerEntry {
     method: Method,
 }
 
-type HandlerMap = HashMap<String, HandlerEntry>;
+enum Segment<'p> {
+    Static(&'p str),
+    Param(&'p str),
+    Wildcard(&'p str),
+}
+
+/// Node of the segment trie routes are stored in. Every edge is one path
+/// segment; a wildcard swallows whatever is left of the path.
+#[derive(Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    param: Option<(String, Box<Node>)>,
+    wildcard: Option<(String, Vec<HandlerEntry>)>,
+    handlers: Vec<HandlerEntry>,
+}
+
+impl Node {
+    fn insert(&mut self, segments: &[Segment], entry: HandlerEntry) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return insert_entry(&mut self.handlers, entry),
+        };
+        match segment {
+            Segment::Static(name) => self
+                .statics
+                .entry(name.to_string())
+                .or_default()
+                .insert(rest, entry),
+            Segment::Param(name) => {
+                let (existing, child) = self
+                    .param
+                    .get_or_insert_with(|| (name.to_string(), Box::default()));
+                assert_eq!(existing, name, "conflicting parameter names in routes");
+                child.insert(rest, entry);
+            }
+            Segment::Wildcard(name) => {
+                assert!(rest.is_empty(), "wildcard must be the last route segment");
+                let (existing, handlers) = self
+                    .wildcard
+                    .get_or_insert_with(|| (name.to_string(), Vec::new()));
+                assert_eq!(existing, name, "conflicting wildcard names in routes");
+                insert_entry(handlers, entry);
+            }
+        }
+    }
+
+    /// Collects every route matching `rest` in precedence order: static
+    /// segments first, then parameters, then wildcards.
+    fn collect<'r>(
+        &'r self,
+        rest: &str,
+        params: &mut Params,
+        out: &mut Vec<(&'r [HandlerEntry], Params)>,
+    ) {
+        if rest.is_empty() {
+            if !self.handlers.is_empty() {
+                out.push((&self.handlers, params.clone()));
+            }
+        } else {
+            let (segment, tail) = rest.split_once('/').unwrap_or((rest, ""));
+            if let Some(child) = self.statics.get(segment) {
+                child.collect(tail, params, out);
+            }
+            if let Some((name, child)) = &self.param {
+                if !segment.is_empty() {
+                    params.push(name, segment);
+                    child.collect(tail, params, out);
+                    params.pop();
+                }
+            }
+        }
+        if let Some((name, handlers)) = &self.wildcard {
+            params.push(name, rest);
+            out.push((handlers, params.clone()));
+            params.pop();
+        }
+    }
+}
+
+/// Registering the same method twice for a route replaces the old handler.
+fn insert_entry(handlers: &mut Vec<HandlerEntry>, entry: HandlerEntry) {
+    handlers.retain(|existing| existing.method != entry.method);
+    handlers.push(entry);
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment<'_>> {
+    pattern
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name)
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name)
+            } else {
+                Segment::Static(segment)
+            }
+        })
+        .collect()
+}
+
+enum Lookup<'r> {
+    Found(&'r HandlerEntry, Params),
+    /// The path matches, but none of its routes take the method. Holds the
+    /// methods they do take.
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
 
 #[derive(Clone)]
 pub struct Router(Arc<RouterInner>);
 
 pub struct RouterInner {
-    exact: HandlerMap,
-    starts_with: Vec<(String, HandlerEntry)>,
+    root: Node,
     middleware: Vec<Middleware>,
+    methods: HashSet<Method>,
 }
 
 impl RouterInner {
-    pub fn exact_route<H>(mut self, mut path: &str, method: Method, handler: H) -> Self
+    /// Adds a route for `pattern`, where `:name` matches a single segment and
+    /// a trailing `*name` matches the rest of the path, e.g.
+    /// `/users/:id/posts/:post_id` or `/files/*path`. Captured values are
+    /// available through [`Request::param`].
+    ///
+    /// When several routes match, static segments take precedence over
+    /// parameters and parameters over wildcards.
+    ///
+    /// Handlers can answer with anything that implements [`IntoResponse`].
+    pub fn route<H, F>(self, pattern: &str, method: Method, handler: H) -> Self
     where
-        H: Fn(Request, State) -> BoxResponseFuture + Send + Sync + 'static,
+        H: Fn(Request, State) -> F + Send + Sync + 'static,
+        F: Future + Send + 'static,
+        F::Output: IntoResponse,
     {
-        if path != "/" {
-            path = path.strip_suffix("/").unwrap_or(path);
-        }
+        let segments = parse_pattern(pattern);
+        self.insert(&segments, method, handler)
+    }
 
-        let mut handler: Handler = Box::new(handler);
-        for middleware in &self.middleware {
-            handler = middleware(handler);
-        }
+    pub fn exact_route<H, F>(self, path: &str, method: Method, handler: H) -> Self
+    where
+        H: Fn(Request, State) -> F + Send + Sync + 'static,
+        F: Future + Send + 'static,
+        F::Output: IntoResponse,
+    {
+        let segments: Vec<_> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(Segment::Static)
+            .collect();
+        self.insert(&segments, method, handler)
+    }
 
-        self.exact
-            .insert(path.to_string(), HandlerEntry { handler, method });
-        self
+    /// Matches every path below `prefix`, same as a `prefix/*` route with an
+    /// unnamed wildcard. Prefixes are matched segment by segment.
+    pub fn starts_with_route<H, F>(self, prefix: &str, method: Method, handler: H) -> Self
+    where
+        H: Fn(Request, State) -> F + Send + Sync + 'static,
+        F: Future + Send + 'static,
+        F::Output: IntoResponse,
+    {
+        let mut segments: Vec<_> = prefix
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(Segment::Static)
+            .collect();
+        segments.push(Segment::Wildcard(""));
+        self.insert(&segments, method, handler)
     }
 
-    pub fn starts_with_route<H>(mut self, path: &str, method: Method, handler: H) -> Self
+    fn insert<H, F>(mut self, segments: &[Segment], method: Method, handler: H) -> Self
     where
-        H: Fn(Request, State) -> BoxResponseFuture + Send + Sync + 'static,
+        H: Fn(Request, State) -> F + Send + Sync + 'static,
+        F: Future + Send + 'static,
+        F::Output: IntoResponse,
     {
-        let mut handler: Handler = Box::new(handler);
+        let mut handler: Handler = Box::new(move |request, state| {
+            let response = handler(request, state);
+            Box::pin(async move { response.await.into_response() })
+        });
         for middleware in &self.middleware {
             handler = middleware(handler);
         }
-        self.starts_with
-            .push((path.to_string(), HandlerEntry { handler, method }));
+
+        self.methods.insert(method.clone());
+        self.root.insert(segments, HandlerEntry { handler, method });
         self
     }
 
@@ -74,42 +221,310 @@ impl RouterInner {
     }
 }
 
+/// Whether a route registered for `route` can answer `requested`. Every GET
+/// route also answers HEAD, the body is dropped when the response is written.
+fn serves(route: &Method, requested: &Method) -> bool {
+    route == requested || (*requested == Method::HEAD && *route == Method::GET)
+}
+
+/// Status and headers of a request the router turns away without running a
+/// handler.
+#[derive(Debug)]
+pub struct Rejection {
+    pub status: StatusCode,
+    pub headers: Headers,
+}
+
+impl Rejection {
+    fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: Headers::new(),
+        }
+    }
+}
+
+/// Value of the `Allow` header required on `405 Method Not Allowed`.
+fn allow_header(allowed: &[Method]) -> String {
+    allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl Router {
+    fn implements(&self, method: &Method) -> bool {
+        self.0.methods.contains(method)
+            || (*method == Method::HEAD && self.0.methods.contains(&Method::GET))
+    }
+
     pub fn builder() -> RouterInner {
         RouterInner {
-            exact: HashMap::new(),
-            starts_with: Vec::new(),
+            root: Node::default(),
             middleware: Vec::new(),
+            methods: HashSet::new(),
         }
     }
 
-    pub async fn handle(&self, request: Request, state: State) -> Response {
-        let mut route_seen_flag = false;
-        let path = &request.metadata.path;
-        let key = match path.as_str() {
-            "/" => "/",
-            _ => path.strip_suffix("/").unwrap_or(path),
-        };
+    fn lookup(&self, path: &str, method: &Method) -> Lookup<'_> {
+        let mut matches = Vec::new();
+        let rest = path.strip_prefix('/').unwrap_or(path);
+        self.0
+            .root
+            .collect(rest, &mut Params::default(), &mut matches);
+        if matches.is_empty() {
+            return Lookup::NotFound;
+        }
 
-        if let Some(handler_entry) = self.0.exact.get(key) {
-            route_seen_flag = true;
-            if handler_entry.method == request.metadata.method {
-                return (handler_entry.handler)(request, state).await;
+        // the first matching route that takes the method wins, an explicit
+        // HEAD route over the one derived from GET
+        let mut allowed = Vec::new();
+        for (entries, params) in matches {
+            let entry = entries
+                .iter()
+                .find(|entry| entry.method == *method)
+                .or_else(|| entries.iter().find(|entry| serves(&entry.method, method)));
+            if let Some(entry) = entry {
+                return Lookup::Found(entry, params);
+            }
+            for entry in entries {
+                if !allowed.contains(&entry.method) {
+                    allowed.push(entry.method.clone());
+                }
             }
         }
+        if allowed.contains(&Method::GET) && !allowed.contains(&Method::HEAD) {
+            allowed.push(Method::HEAD);
+        }
+        Lookup::MethodNotAllowed(allowed)
+    }
 
-        for (prefix, handler_entry) in &self.0.starts_with {
-            if path.starts_with(prefix) {
-                route_seen_flag = true;
-                if handler_entry.method == request.metadata.method {
-                    return (handler_entry.handler)(request, state).await;
-                }
+    /// Whether a request for `path` with `method` would reach a handler,
+    /// otherwise how the router would answer it.
+    pub fn check(&self, path: &str, method: &Method) -> Result<(), Rejection> {
+        if !self.implements(method) {
+            return Err(Rejection::new(StatusCode::NotImplemented));
+        }
+        match self.lookup(path, method) {
+            Lookup::Found(..) => Ok(()),
+            Lookup::MethodNotAllowed(allowed) => {
+                let mut rejection = Rejection::new(StatusCode::MethodNotAllowed);
+                rejection
+                    .headers
+                    .insert("Allow".to_string(), allow_header(&allowed));
+                Err(rejection)
+            }
+            Lookup::NotFound => Err(Rejection::new(StatusCode::NotFound)),
+        }
+    }
+
+    pub async fn handle(&self, mut request: Request, state: State) -> Response {
+        // a method no route was ever registered for is unknown to the server
+        if !self.implements(&request.metadata.method) {
+            return handlers::not_implemented_handler(request, state).await;
+        }
+
+        match self.lookup(&request.metadata.path, &request.metadata.method) {
+            Lookup::Found(entry, params) => {
+                request.params = params;
+                (entry.handler)(request, state).await
+            }
+            Lookup::MethodNotAllowed(allowed) => {
+                let mut response = handlers::method_not_allowed_handler(request, state).await;
+                let headers = response.headers.get_or_insert_with(Headers::new);
+                headers.insert("Allow".to_string(), allow_header(&allowed));
+                response
             }
+            Lookup::NotFound => handlers::not_found_handler(request, state).await,
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::http::request::{Metadata, Trailers};
 
-        if route_seen_flag {
-            return handlers::method_not_allowed_handler(request, state).await;
+    fn request(method: Method, path: &str) -> Request {
+        Request {
+            metadata: Metadata::new(method, path.to_string(), Headers::new()),
+            body: None,
+            trailers: Trailers::default(),
+            params: Params::default(),
         }
-        handlers::not_found_handler(request, state).await
+    }
+
+    async fn status(router: &Router, method: Method, path: &str) -> StatusCode {
+        let state = State::builder().build();
+        router.handle(request(method, path), state).await.status
+    }
+
+    #[tokio::test]
+    async fn method_dispatch() {
+        let router = Router::builder()
+            .exact_route("/", Method::GET, handlers::ok_handler)
+            .exact_route("/items", Method::DELETE, handlers::ok_handler)
+            .build();
+
+        assert_eq!(
+            status(&router, Method::DELETE, "/items").await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(&router, Method::DELETE, "/").await,
+            StatusCode::MethodNotAllowed
+        );
+        assert_eq!(
+            status(&router, Method::PUT, "/items").await,
+            StatusCode::NotImplemented
+        );
+        assert_eq!(
+            status(&router, Method::Extension("BREW".to_string()), "/").await,
+            StatusCode::NotImplemented
+        );
+    }
+
+    #[tokio::test]
+    async fn allow_on_method_not_allowed() {
+        let router = Router::builder()
+            .route("/files/*path", Method::GET, handlers::ok_handler)
+            .route("/files/*path", Method::PUT, handlers::ok_handler)
+            .exact_route("/files/locked", Method::POST, handlers::ok_handler)
+            .exact_route("/items", Method::DELETE, handlers::ok_handler)
+            .build();
+        let state = State::builder().build();
+        let response = router
+            .handle(request(Method::DELETE, "/files/locked"), state)
+            .await;
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        let allow = response.headers.as_ref().and_then(|h| h.get("Allow"));
+        assert_eq!(allow, Some("POST, GET, PUT, HEAD"));
+
+        let rejection = router.check("/files/locked", &Method::DELETE).unwrap_err();
+        assert_eq!(rejection.headers.get("Allow"), Some("POST, GET, PUT, HEAD"));
+    }
+
+    #[tokio::test]
+    async fn head_from_get() {
+        let router = Router::builder()
+            .starts_with_route("/echo/", Method::GET, handlers::ok_handler)
+            .starts_with_route("/files/", Method::POST, handlers::ok_handler)
+            .starts_with_route("/files/", Method::HEAD, handlers::not_found_handler)
+            .starts_with_route("/files/", Method::GET, handlers::ok_handler)
+            .build();
+
+        assert_eq!(
+            status(&router, Method::HEAD, "/echo/abc").await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(&router, Method::HEAD, "/files/abc").await,
+            StatusCode::NotFound
+        );
+    }
+
+    fn echo_param(name: &'static str) -> impl Fn(Request, State) -> BoxResponseFuture {
+        move |request: Request, _state: State| {
+            let value = request.param(name).unwrap_or("<none>").to_string();
+            Box::pin(async move {
+                Response::from_data(StatusCode::Ok, Headers::new(), value.into_bytes())
+            })
+        }
+    }
+
+    async fn body(router: &Router, path: &str) -> Option<String> {
+        let state = State::builder().build();
+        let response = router.handle(request(Method::GET, path), state).await;
+        if response.status != StatusCode::Ok {
+            return None;
+        }
+        let data = response.body.unwrap().into_bytes().await.unwrap();
+        Some(String::from_utf8(data).unwrap())
+    }
+
+    #[tokio::test]
+    async fn path_parameters() {
+        let router = Router::builder()
+            .route("/users/:id", Method::GET, echo_param("id"))
+            .route(
+                "/users/:id/posts/:post_id",
+                Method::GET,
+                echo_param("post_id"),
+            )
+            .route("/files/*path", Method::GET, echo_param("path"))
+            .build();
+
+        assert_eq!(body(&router, "/users/42").await.as_deref(), Some("42"));
+        assert_eq!(body(&router, "/users/42/").await.as_deref(), Some("42"));
+        assert_eq!(
+            body(&router, "/users/42/posts/7").await.as_deref(),
+            Some("7")
+        );
+        assert_eq!(body(&router, "/users/42/comments").await, None);
+        assert_eq!(body(&router, "/users/").await, None);
+        assert_eq!(
+            body(&router, "/files/a/b/c.txt").await.as_deref(),
+            Some("a/b/c.txt")
+        );
+        assert_eq!(body(&router, "/files/dir/").await.as_deref(), Some("dir/"));
+        assert_eq!(body(&router, "/files/").await.as_deref(), Some(""));
+    }
+
+    #[tokio::test]
+    async fn route_precedence() {
+        let router = Router::builder()
+            .route("/files/*path", Method::GET, echo_param("path"))
+            .route("/files/:name", Method::GET, echo_param("name"))
+            .route("/files/latest", Method::GET, echo_param("name"))
+            .route("/files/:name/meta", Method::GET, echo_param("name"))
+            .build();
+
+        assert_eq!(
+            body(&router, "/files/latest").await.as_deref(),
+            Some("<none>")
+        );
+        assert_eq!(
+            body(&router, "/files/a.txt").await.as_deref(),
+            Some("a.txt")
+        );
+        assert_eq!(
+            body(&router, "/files/a.txt/meta").await.as_deref(),
+            Some("a.txt")
+        );
+        assert_eq!(
+            body(&router, "/files/latest/meta").await.as_deref(),
+            Some("latest")
+        );
+        assert_eq!(body(&router, "/files/a/b").await.as_deref(), Some("a/b"));
+    }
+
+    #[tokio::test]
+    async fn into_response_handlers() {
+        let router = Router::builder()
+            .exact_route("/text", Method::GET, |_, _| async { "hello" })
+            .route(
+                "/items/:id",
+                Method::GET,
+                |request: Request, _| async move {
+                    match request.param("id") {
+                        Some("1") => Ok((StatusCode::Accepted, "one".to_string())),
+                        _ => Err(StatusCode::NotFound),
+                    }
+                },
+            )
+            .build();
+
+        assert_eq!(status(&router, Method::GET, "/text").await, StatusCode::Ok);
+        assert_eq!(
+            status(&router, Method::GET, "/items/1").await,
+            StatusCode::Accepted
+        );
+        assert_eq!(
+            status(&router, Method::GET, "/items/2").await,
+            StatusCode::NotFound
+        );
     }
 }
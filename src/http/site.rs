@@ -0,0 +1,169 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs;
+
+use crate::http::helpers;
+
+/// How requests for directories below the file directory are answered.
+#[derive(Clone, Debug)]
+pub struct StaticSite {
+    /// File served for a directory that contains it.
+    pub index: String,
+    /// List the contents of directories without an index file.
+    pub listing: bool,
+}
+
+impl Default for StaticSite {
+    fn default() -> Self {
+        Self {
+            index: "index.html".to_string(),
+            listing: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Reads the entries of `dir`, directories first and each group sorted by
+/// name. Hidden entries and names that are not UTF-8 are left out.
+pub async fn read_dir(dir: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        // follows symlinks, like serving the entry would
+        let metadata = match fs::metadata(entry.path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Renders a listing of `entries` for the directory at the request path
+/// `path`, linking relative to it.
+pub fn html(path: &str, entries: &[Entry]) -> String {
+    let title = helpers::html_escape(path);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n"
+    );
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let href = helpers::percent_encode(&entry.name);
+        let name = helpers::html_escape(&entry.name);
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry.modified.map(helpers::http_date).unwrap_or_default();
+        out.push_str(&format!(
+            "<tr><td><a href=\"{href}{slash}\">{name}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>\n"
+        ));
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+/// Renders `entries` as a JSON array, with modification times in seconds
+/// since the epoch.
+pub fn json(entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".to_string(), |d| d.as_secs().to_string());
+            format!(
+                "{{\"name\":{},\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                helpers::json_string(&entry.name),
+                if entry.is_dir { "directory" } else { "file" },
+                entry.size,
+                modified
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry {
+                name: "sub dir".to_string(),
+                is_dir: true,
+                size: 0,
+                modified: None,
+            },
+            Entry {
+                name: "<b>.txt".to_string(),
+                is_dir: false,
+                size: 3,
+                modified: Some(UNIX_EPOCH + Duration::from_secs(60)),
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn sorted_entries() -> std::io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("site-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("b"))?;
+        std::fs::write(dir.join("a.txt"), "a")?;
+        std::fs::write(dir.join("c.txt"), "cc")?;
+        std::fs::write(dir.join(".hidden"), "")?;
+
+        let names: Vec<_> = read_dir(&dir)
+            .await?
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["b", "a.txt", "c.txt"]);
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let out = html("/files/<x>/", &entries());
+        assert!(out.contains("<title>Index of /files/&lt;x&gt;/</title>"));
+        assert!(out.contains("<a href=\"sub%20dir/\">sub dir/</a></td><td>-</td>"));
+        assert!(out.contains(
+            "<a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a></td><td>3</td><td>Thu, 01 Jan 1970 00:01:00 GMT</td>"
+        ));
+    }
+
+    #[test]
+    fn json_listing() {
+        assert_eq!(
+            json(&entries()),
+            "[{\"name\":\"sub dir\",\"type\":\"directory\",\"size\":0,\"modified\":null},\
+             {\"name\":\"<b>.txt\",\"type\":\"file\",\"size\":3,\"modified\":60}]"
+        );
+    }
+}
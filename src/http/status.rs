@@ -1,30 +1,162 @@
 use std::fmt;
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum StatusCode {
-    Ok = 200,
-    Created = 201,
-    NotFound = 404,
-    MethodNotAllowed = 405,
-    Internal = 500,
+macro_rules! status_codes {
+    ($($name:ident = $code:literal, $reason:literal;)*) => {
+        /// Status of a response: every code in the IANA registry, plus
+        /// [`StatusCode::Other`] for the rest of 100 to 599.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum StatusCode {
+            $($name,)*
+            /// A code without a registered meaning, see [`Unregistered`].
+            Other(Unregistered),
+        }
+
+        impl StatusCode {
+            /// Status for `code`, if it is a valid three digit status.
+            pub fn from_u16(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(Self::$name),)*
+                    100..=599 => Some(Self::Other(Unregistered(code))),
+                    _ => None,
+                }
+            }
+
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(Self::$name => $code,)*
+                    Self::Other(other) => other.0,
+                }
+            }
+
+            /// Canonical reason phrase, `None` for unregistered codes.
+            pub fn reason(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$name => Some($reason),)*
+                    Self::Other(_) => None,
+                }
+            }
+        }
+    };
+}
+
+/// Code of a [`StatusCode::Other`]. Only [`StatusCode::from_u16`] makes
+/// one, so it is always in range and never a registered code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Unregistered(u16);
+
+impl Unregistered {
+    pub fn code(&self) -> u16 {
+        self.0
+    }
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+
+    Internal = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl StatusCode {
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.code())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.code())
+    }
+
+    /// Whether a response with this status may carry a body, which 1xx,
+    /// 204 and 304 responses never do.
+    pub fn allows_body(&self) -> bool {
+        !(self.is_informational() || matches!(self.code(), 204 | 304))
+    }
 }
 
-impl Into<u16> for StatusCode {
-    fn into(self) -> u16 {
-        self as u16
+impl From<StatusCode> for u16 {
+    fn from(status: StatusCode) -> Self {
+        status.code()
     }
 }
 
+/// Code and reason phrase as they appear in the status line. The phrase is
+/// empty for unregistered codes.
 impl fmt::Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let line = match self {
-            Self::Ok => "200 OK",
-            Self::Created => "201 Created",
-            Self::NotFound => "404 Not Found",
-            Self::MethodNotAllowed => "405 Method Not Allowed",
-            Self::Internal => "500 Internal Server Error",
-        };
-        write!(f, "{line}")
+        write!(f, "{} {}", self.code(), self.reason().unwrap_or(""))
     }
 }
 
@@ -36,6 +168,50 @@ mod tests {
 
     #[test]
     fn as_int() {
-        assert_eq!(StatusCode::Ok as u16, 200);
+        assert_eq!(u16::from(StatusCode::Ok), 200);
+        assert_eq!(StatusCode::NetworkAuthenticationRequired.code(), 511);
+    }
+
+    #[test]
+    fn from_int() {
+        assert_eq!(
+            StatusCode::from_u16(503),
+            Some(StatusCode::ServiceUnavailable)
+        );
+        assert!(matches!(
+            StatusCode::from_u16(404),
+            Some(StatusCode::NotFound)
+        ));
+        assert!(matches!(
+            StatusCode::from_u16(299),
+            Some(StatusCode::Other(other)) if other.code() == 299
+        ));
+        assert_eq!(StatusCode::from_u16(99), None);
+        assert_eq!(StatusCode::from_u16(600), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(StatusCode::Ok.to_string(), "200 OK");
+        assert_eq!(
+            StatusCode::ContentTooLarge.to_string(),
+            "413 Content Too Large"
+        );
+        assert_eq!(StatusCode::from_u16(299).unwrap().to_string(), "299 ");
+    }
+
+    #[test]
+    fn classes() {
+        assert!(StatusCode::EarlyHints.is_informational());
+        assert!(StatusCode::from_u16(299).unwrap().is_success());
+        assert!(StatusCode::PermanentRedirect.is_redirection());
+        assert!(StatusCode::TooManyRequests.is_client_error());
+        assert!(StatusCode::BadGateway.is_server_error());
+        assert!(!StatusCode::NotFound.is_success());
+
+        assert!(!StatusCode::Continue.allows_body());
+        assert!(!StatusCode::NoContent.allows_body());
+        assert!(!StatusCode::NotModified.allows_body());
+        assert!(StatusCode::ResetContent.allows_body());
     }
 }
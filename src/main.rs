@@ -13,26 +13,37 @@ async fn main() -> Result<()> {
         .await
         .with_context(|| format!("failed to bind to {}", addr))?;
 
+    let mut config = http::Config::default();
     let mut args = env::args().skip(1);
-    let dir_flag = match args.next() {
-        Some(flag) if flag == "--directory" => true,
-        Some(_) => {
-            println!("--directory is the only valid flag");
-            return Err(anyhow!("invalid input"));
-        }
-        None => false,
-    };
-
-    let mut directory = None;
-    if dir_flag {
-        if let Some(dir) = args.next() {
-            directory = Some(dir);
-        } else {
-            println!("Usage: --directory <directory>");
-            return Err(anyhow!("missing directory name"));
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--directory" => match args.next() {
+                Some(dir) => config.file_dir = Some(dir),
+                None => {
+                    println!("Usage: --directory <directory>");
+                    return Err(anyhow!("missing directory name"));
+                }
+            },
+            // serve index.html for directories below --directory
+            "--static-site" => {
+                config.static_site.get_or_insert_with(Default::default);
+            }
+            // list directories without an index, implies --static-site
+            "--listing" => {
+                config
+                    .static_site
+                    .get_or_insert_with(Default::default)
+                    .listing = true;
+            }
+            // create missing directories for uploads
+            "--create-dirs" => config.create_dirs = true,
+            _ => {
+                println!("valid flags are --directory, --static-site, --listing and --create-dirs");
+                return Err(anyhow!("invalid input"));
+            }
         }
     }
 
-    http::run_server(listener, directory).await;
+    http::run_server(listener, config).await;
     Ok(())
 }